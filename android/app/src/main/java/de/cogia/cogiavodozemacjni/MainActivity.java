@@ -12,11 +12,12 @@ import org.json.JSONException;
 
 import de.cogia.vodozemac.IdentityKeys;
 import de.cogia.vodozemac.InboundCreationResult;
+import de.cogia.vodozemac.MessageDecodeException;
 import de.cogia.vodozemac.OlmAccount;
 import de.cogia.vodozemac.OlmException;
 import de.cogia.vodozemac.OlmMessage;
 import de.cogia.vodozemac.OlmSession;
-import de.cogia.vodozemac.SessionConfig;
+import de.cogia.vodozemac.OlmSessionConfig;
 
 public class MainActivity extends AppCompatActivity {
 
@@ -28,9 +29,9 @@ public class MainActivity extends AppCompatActivity {
     @Override
     protected void onCreate(Bundle savedInstanceState) {
 
-        SessionConfig sessionConfig = SessionConfig.version1();
+        OlmSessionConfig sessionConfig = OlmSessionConfig.version1();
         System.out.println(sessionConfig.version());
-        System.out.println(SessionConfig.version2().version());
+        System.out.println(OlmSessionConfig.version2().version());
 
         OlmAccount olmAccount = new OlmAccount();
         try {
@@ -80,7 +81,7 @@ public class MainActivity extends AppCompatActivity {
         String[] bobOnetimeKeys = bob.oneTimeKeys().values().toArray(new String[0]);
         String bobFirstOnetimeKey = bobOnetimeKeys[0];
 
-        OlmSession session = alice.createOutboundSession(bob.curve25519Key(), bobFirstOnetimeKey, SessionConfig.version2());
+        OlmSession session = alice.createOutboundSession(bob.curve25519Key(), bobFirstOnetimeKey, OlmSessionConfig.version2());
         System.out.println(session.sessionId());
         OlmMessage res = session.encrypt("Hello there");
         InboundCreationResult iRes = bob.createInboundSession(alice.curve25519Key(), res);
@@ -92,15 +93,18 @@ public class MainActivity extends AppCompatActivity {
 
 
         try {
-            String decrypted2 = session.decrypt(new OlmMessage("asdasdasd", 0));
+            String decrypted2 = session.decrypt(OlmMessage.fromParts(OlmMessage.Type.PRE_KEY, "asdasdasd"));
             System.out.println(message.getCiphertext() == decrypted2);
+        } catch (MessageDecodeException e) {
+            // Malformed ciphertexts are rejected before they reach a session.
+            System.out.println(e);
         } catch (Exception e) {
             System.out.println(e);
         }
 
         try {
-            boolean decrypted2 = session.sessionMatches(new OlmMessage("asdasdasd", 0));
-        } catch (Exception e) {
+            boolean decrypted2 = session.sessionMatches(OlmMessage.fromParts(OlmMessage.Type.PRE_KEY, "asdasdasd"));
+        } catch (MessageDecodeException e) {
             System.out.println(e);
         }
 
@@ -0,0 +1,41 @@
+package de.cogia.vodozemac;
+
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+public class Curve25519PublicKey implements AutoCloseable {
+
+    private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
+
+    private static native void _free(final long ptr);
+    private static native long _from_base64(final String key) throws OlmException;
+    private static native long _from_bytes(final byte[] key) throws OlmException;
+    private static native String _to_base64(final long ptr);
+    private static native byte[] _to_bytes(final long ptr);
+
+    Curve25519PublicKey(final long ptr) {
+        this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, Curve25519PublicKey::_free);
+    }
+
+    public static Curve25519PublicKey fromBase64(final String key) throws OlmException {
+        return new Curve25519PublicKey(_from_base64(key));
+    }
+
+    public static Curve25519PublicKey fromBytes(final byte[] key) throws OlmException {
+        return new Curve25519PublicKey(_from_bytes(key));
+    }
+
+    public String toBase64() {
+        return _to_base64(ptr);
+    }
+
+    public byte[] toBytes() {
+        return _to_bytes(ptr);
+    }
+
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
+}
@@ -0,0 +1,29 @@
+package de.cogia.vodozemac;
+
+/** The decrypted payload of an {@code m.room.encrypted} event. */
+public class DecryptedRoomEvent {
+
+    private final String type;
+    private final String content;
+    private final long messageIndex;
+
+    public DecryptedRoomEvent(final String type, final String content, final long messageIndex) {
+        this.type = type;
+        this.content = content;
+        this.messageIndex = messageIndex;
+    }
+
+    /** The type of the decrypted event, e.g. {@code m.room.message}. */
+    public String getType() {
+        return type;
+    }
+
+    /** The content of the decrypted event as a JSON object. */
+    public String getContent() {
+        return content;
+    }
+
+    public long getMessageIndex() {
+        return messageIndex;
+    }
+}
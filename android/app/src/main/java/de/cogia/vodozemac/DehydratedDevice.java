@@ -0,0 +1,23 @@
+package de.cogia.vodozemac;
+
+/** An {@link OlmAccount} encrypted as an MSC3814 dehydrated device. */
+public class DehydratedDevice {
+
+    private final String ciphertext;
+    private final String nonce;
+
+    public DehydratedDevice(final String ciphertext, final String nonce) {
+        this.ciphertext = ciphertext;
+        this.nonce = nonce;
+    }
+
+    /** The unpadded base64 encrypted account, the {@code device_pickle} to upload. */
+    public String getCiphertext() {
+        return ciphertext;
+    }
+
+    /** The unpadded base64 nonce the account was encrypted with. */
+    public String getNonce() {
+        return nonce;
+    }
+}
@@ -0,0 +1,77 @@
+package de.cogia.vodozemac;
+
+import java.nio.charset.StandardCharsets;
+
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+public class Ed25519PublicKey implements AutoCloseable {
+
+    private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
+
+    private static native void _free(final long ptr);
+    private static native long _from_base64(final String key) throws OlmException;
+    private static native long _from_bytes(final byte[] key) throws OlmException;
+    private static native String _to_base64(final long ptr);
+    private static native byte[] _to_bytes(final long ptr);
+    private static native void _verify(final long ptr, final byte[] message, final String signature) throws OlmException;
+    private static native void _verify_bytes(final long ptr, final byte[] message, final byte[] signature) throws OlmException;
+    private static native void _verify_json(final long ptr, final String json, final String userId,
+                                            final String deviceId) throws OlmException;
+
+    private Ed25519PublicKey(final long ptr) {
+        this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, Ed25519PublicKey::_free);
+    }
+
+    public static Ed25519PublicKey fromBase64(final String key) throws OlmException {
+        return new Ed25519PublicKey(_from_base64(key));
+    }
+
+    public static Ed25519PublicKey fromBytes(final byte[] key) throws OlmException {
+        return new Ed25519PublicKey(_from_bytes(key));
+    }
+
+    public String toBase64() {
+        return _to_base64(ptr);
+    }
+
+    public byte[] toBytes() {
+        return _to_bytes(ptr);
+    }
+
+    /**
+     * Verifies an unpadded base64 signature over {@code message}.
+     *
+     * @throws InvalidSignatureException if the signature is malformed or doesn't match
+     */
+    public void verify(final byte[] message, final String signature) throws OlmException {
+        _verify(ptr, message, signature);
+    }
+
+    /** Verifies a signature over the UTF-8 bytes of {@code message}. */
+    public void verify(final String message, final String signature) throws OlmException {
+        verify(message.getBytes(StandardCharsets.UTF_8), signature);
+    }
+
+    /** Verifies a raw 64 byte signature over {@code message}. */
+    public void verify(final byte[] message, final byte[] signature) throws OlmException {
+        _verify_bytes(ptr, message, signature);
+    }
+
+    /**
+     * Verifies the {@code signatures.<userId>.ed25519:<deviceId>} signature of a
+     * signed JSON object, e.g. the device keys of another user. For cross-signing
+     * keys pass the unpadded base64 public key as {@code deviceId}.
+     *
+     * @throws InvalidSignatureException if the signature is missing, malformed or doesn't match
+     */
+    public void verifyJson(final String json, final String userId, final String deviceId) throws OlmException {
+        _verify_json(ptr, json, userId, deviceId);
+    }
+
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
+}
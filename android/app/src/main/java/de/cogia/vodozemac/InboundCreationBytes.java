@@ -0,0 +1,22 @@
+package de.cogia.vodozemac;
+
+/** The outcome of {@link OlmAccount#createInboundSessionBytes}. */
+public class InboundCreationBytes {
+
+    private final OlmSession olmSession;
+    private final byte[] plainText;
+
+    public InboundCreationBytes(final byte[] text, final long sessionPtr) {
+        olmSession = new OlmSession(sessionPtr);
+        plainText = text;
+    }
+
+    public byte[] getPlainText() {
+        return plainText;
+    }
+
+    public OlmSession getSession() {
+        return olmSession;
+    }
+
+}
@@ -0,0 +1,8 @@
+package de.cogia.vodozemac;
+
+/** An Ed25519 signature is malformed or doesn't match the signed message. */
+public class InvalidSignatureException extends OlmException {
+    public InvalidSignatureException(String message) {
+        super(message);
+    }
+}
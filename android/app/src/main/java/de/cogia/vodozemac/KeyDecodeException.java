@@ -0,0 +1,16 @@
+package de.cogia.vodozemac;
+
+/** A key passed to the library couldn't be decoded. */
+public class KeyDecodeException extends OlmException {
+    private final String keyType;
+
+    public KeyDecodeException(String message, String keyType) {
+        super(message);
+        this.keyType = keyType;
+    }
+
+    /** The kind of key that was rejected, e.g. {@code "curve25519"}. */
+    public String getKeyType() {
+        return keyType;
+    }
+}
@@ -0,0 +1,47 @@
+package de.cogia.vodozemac;
+
+import java.util.Arrays;
+import java.util.Collections;
+import java.util.List;
+
+/**
+ * Moves a libolm store over to vodozemac: libolm pickles are decrypted with
+ * the old libolm pickle key and pickled again under a new {@link PickleKey}.
+ * <p>
+ * A whole batch is migrated in one native call. Each pickle is migrated on its
+ * own, so a broken one doesn't stop the rest of the batch.
+ */
+public final class LibolmMigration {
+
+    /** What a libolm pickle holds, libolm pickles don't record it themselves. */
+    public enum PickleKind {
+        ACCOUNT(0),
+        SESSION(1),
+        INBOUND_GROUP_SESSION(2),
+        GROUP_SESSION(3);
+
+        private final int code;
+
+        PickleKind(final int code) {
+            this.code = code;
+        }
+    }
+
+    private static native LibolmMigrationResult[] _migrate(final int kind, final String[] pickles,
+                                                           final byte[] oldKey, final long newKey) throws OlmException;
+
+    private LibolmMigration() {
+    }
+
+    /**
+     * Migrates {@code pickles}, which all have to be of the same {@code kind}.
+     *
+     * @return one result per pickle, in the same order
+     */
+    public static List<LibolmMigrationResult> migrate(final PickleKind kind, final List<String> pickles,
+                                                      final byte[] oldKey, final PickleKey newKey) throws OlmException {
+        final LibolmMigrationResult[] results =
+                _migrate(kind.code, pickles.toArray(new String[0]), oldKey, newKey.getPtr());
+        return Collections.unmodifiableList(Arrays.asList(results));
+    }
+}
@@ -0,0 +1,37 @@
+package de.cogia.vodozemac;
+
+/** The outcome of migrating a single libolm pickle with {@link LibolmMigration}. */
+public class LibolmMigrationResult {
+
+    private final String id;
+    private final String pickle;
+    private final String error;
+
+    LibolmMigrationResult(final String id, final String pickle, final String error) {
+        this.id = id;
+        this.pickle = pickle;
+        this.error = error;
+    }
+
+    public boolean isSuccess() {
+        return error == null;
+    }
+
+    /**
+     * The Curve25519 key of a migrated account or the session id of a migrated
+     * session, {@code null} if the migration failed.
+     */
+    public String getId() {
+        return id;
+    }
+
+    /** The vodozemac pickle, {@code null} if the migration failed. */
+    public String getPickle() {
+        return pickle;
+    }
+
+    /** Why the migration failed, {@code null} if it succeeded. */
+    public String getError() {
+        return error;
+    }
+}
@@ -0,0 +1,8 @@
+package de.cogia.vodozemac;
+
+/** A Megolm message couldn't be decrypted. */
+public class MegolmDecryptionException extends OlmException {
+    public MegolmDecryptionException(String message) {
+        super(message);
+    }
+}
@@ -0,0 +1,83 @@
+package de.cogia.vodozemac;
+
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+/**
+ * An encrypted Megolm message. Its parts can be read without a session, e.g.
+ * a session covers the message if its {@link OlmInboundGroupSession#firstKnownIndex()}
+ * is at most {@link #getMessageIndex()}.
+ */
+public class MegolmMessage implements AutoCloseable {
+
+    private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
+
+    private static native void _free(final long ptr);
+    private static native long _from_base64(final String message) throws OlmException;
+    private static native long _from_bytes(final byte[] message) throws OlmException;
+    private static native long _session_config(final long ptr);
+    private static native long _message_index(final long ptr);
+    private static native byte[] _ciphertext(final long ptr);
+    private static native byte[] _mac(final long ptr);
+    private static native String _signature(final long ptr);
+    private static native String _to_base64(final long ptr);
+    private static native byte[] _to_bytes(final long ptr);
+
+    MegolmMessage(final long ptr) {
+        this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, MegolmMessage::_free);
+    }
+
+    /** Decodes the {@code ciphertext} of an {@code m.megolm.v1.aes-sha2} event. */
+    public static MegolmMessage fromBase64(final String message) throws OlmException {
+        return new MegolmMessage(_from_base64(message));
+    }
+
+    public static MegolmMessage fromBytes(final byte[] message) throws OlmException {
+        return new MegolmMessage(_from_bytes(message));
+    }
+
+    long getPtr() {
+        return ptr;
+    }
+
+    public long getMessageIndex() {
+        return _message_index(ptr);
+    }
+
+    /**
+     * The Megolm version the message was encrypted with, v2 messages carry the
+     * full MAC instead of a truncated one.
+     */
+    public MegolmSessionConfig sessionConfig() {
+        return new MegolmSessionConfig(_session_config(ptr));
+    }
+
+    /** The AES-CBC encrypted plaintext, without the MAC and the signature. */
+    public byte[] getCiphertext() {
+        return _ciphertext(ptr);
+    }
+
+    public byte[] getMac() {
+        return _mac(ptr);
+    }
+
+    /** The signature of the session's Ed25519 key, in unpadded base64. */
+    public String getSignature() {
+        return _signature(ptr);
+    }
+
+    /** The message as it goes into the {@code ciphertext} of an event. */
+    public String toBase64() {
+        return _to_base64(ptr);
+    }
+
+    public byte[] toBytes() {
+        return _to_bytes(ptr);
+    }
+
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
+}
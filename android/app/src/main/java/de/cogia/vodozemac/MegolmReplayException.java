@@ -0,0 +1,31 @@
+package de.cogia.vodozemac;
+
+/**
+ * A Megolm message index was already decrypted for another event, the
+ * ciphertext was most likely replayed by the server.
+ */
+public class MegolmReplayException extends MegolmDecryptionException {
+    private final String sessionId;
+    private final long messageIndex;
+    private final String previousEventId;
+
+    public MegolmReplayException(String message, String sessionId, long messageIndex, String previousEventId) {
+        super(message);
+        this.sessionId = sessionId;
+        this.messageIndex = messageIndex;
+        this.previousEventId = previousEventId;
+    }
+
+    public String getSessionId() {
+        return sessionId;
+    }
+
+    public long getMessageIndex() {
+        return messageIndex;
+    }
+
+    /** The event the message index was first decrypted for. */
+    public String getPreviousEventId() {
+        return previousEventId;
+    }
+}
@@ -0,0 +1,107 @@
+package de.cogia.vodozemac;
+
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+/**
+ * Remembers which event every decrypted Megolm message index belonged to, so
+ * a ciphertext the server replays under a new event id is rejected.
+ * Decrypting the same event again is fine.
+ *
+ * <p>One guard can serve any number of sessions, pickle it together with them.
+ * Records are only dropped by {@link #forgetSession(String)} and
+ * {@link #pruneBefore(long)}, a guard that is never pruned keeps growing.
+ */
+public class MegolmReplayGuard implements AutoCloseable {
+
+    private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
+
+    private static native long _new();
+    private static native void _free(final long ptr);
+    private static native void _record(final long ptr, final String sessionId, final long messageIndex,
+                                       final String eventId, final long timestamp) throws OlmException;
+    private static native OlmDecryptedMessage _decrypt(final long ptr, final long session, final String ciphertext,
+                                                       final String eventId, final long timestamp) throws OlmException;
+    private static native OlmDecryptedBytes _decrypt_bytes(final long ptr, final long session, final String ciphertext,
+                                                           final String eventId, final long timestamp) throws OlmException;
+    private static native void _forget_session(final long ptr, final String sessionId);
+    private static native long _prune_before(final long ptr, final long timestamp) throws OlmException;
+    private static native long _record_count(final long ptr);
+    private static native String _pickle(final long ptr, final long pickleKey) throws OlmException;
+    private static native long _from_pickle(final String pickle, final long pickleKey) throws OlmException;
+
+    public MegolmReplayGuard() {
+        this(_new());
+    }
+
+    private MegolmReplayGuard(final long ptr) {
+        this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, MegolmReplayGuard::_free);
+    }
+
+    /**
+     * Records a message index decrypted elsewhere.
+     *
+     * @param timestamp the {@code origin_server_ts} of the event
+     * @throws MegolmReplayException if the index already decrypted another event
+     */
+    public void record(final String sessionId, final long messageIndex, final String eventId,
+                       final long timestamp) throws OlmException {
+        _record(ptr, sessionId, messageIndex, eventId, timestamp);
+    }
+
+    /**
+     * Decrypts {@code ciphertext} with {@code session} and records its message
+     * index, a failed decryption records nothing.
+     *
+     * @param timestamp the {@code origin_server_ts} of the event
+     * @throws MegolmReplayException if the index already decrypted another event
+     */
+    public OlmDecryptedMessage decrypt(final OlmInboundGroupSession session, final String ciphertext,
+                                       final String eventId, final long timestamp) throws OlmException {
+        return _decrypt(ptr, session.getPtr(), ciphertext, eventId, timestamp);
+    }
+
+    public OlmDecryptedBytes decryptBytes(final OlmInboundGroupSession session, final String ciphertext,
+                                          final String eventId, final long timestamp) throws OlmException {
+        return _decrypt_bytes(ptr, session.getPtr(), ciphertext, eventId, timestamp);
+    }
+
+    /** Drops the records of a session, e.g. once the session itself is deleted. */
+    public void forgetSession(final String sessionId) {
+        _forget_session(ptr, sessionId);
+    }
+
+    /**
+     * Drops the records of every event sent before {@code timestamp}. Replays
+     * of those events can't be caught anymore, so ignore events that old.
+     *
+     * @param timestamp an {@code origin_server_ts}
+     * @return the number of dropped records
+     */
+    public long pruneBefore(final long timestamp) throws OlmException {
+        return _prune_before(ptr, timestamp);
+    }
+
+    /** The number of recorded message indices over all sessions. */
+    public long recordCount() {
+        return _record_count(ptr);
+    }
+
+    public String pickle(final PickleKey pickleKey) throws OlmException {
+        return _pickle(ptr, pickleKey.getPtr());
+    }
+
+    public static MegolmReplayGuard fromPickle(final String pickle, final PickleKey pickleKey) throws OlmException {
+        return new MegolmReplayGuard(_from_pickle(pickle, pickleKey.getPtr()));
+    }
+
+    long getPtr() {
+        return ptr;
+    }
+
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
+}
@@ -0,0 +1,24 @@
+package de.cogia.vodozemac;
+
+/**
+ * A Megolm message was encrypted at an index the inbound session doesn't
+ * know, i.e. one before its first known index.
+ */
+public class MegolmUnknownIndexException extends MegolmDecryptionException {
+    private final long firstKnownIndex;
+    private final long messageIndex;
+
+    public MegolmUnknownIndexException(String message, long firstKnownIndex, long messageIndex) {
+        super(message);
+        this.firstKnownIndex = firstKnownIndex;
+        this.messageIndex = messageIndex;
+    }
+
+    public long getFirstKnownIndex() {
+        return firstKnownIndex;
+    }
+
+    public long getMessageIndex() {
+        return messageIndex;
+    }
+}
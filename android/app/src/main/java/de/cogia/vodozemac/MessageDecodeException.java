@@ -0,0 +1,8 @@
+package de.cogia.vodozemac;
+
+/** A message or MAC isn't valid base64 or isn't a well formed Olm/Megolm message. */
+public class MessageDecodeException extends OlmException {
+    public MessageDecodeException(String message) {
+        super(message);
+    }
+}
@@ -1,68 +1,144 @@
 package de.cogia.vodozemac;
 
-import org.json.JSONException;
-import org.json.JSONObject;
+import de.cogia.vodozemac.internal.NativeCleaner;
 
+import java.nio.charset.StandardCharsets;
+import java.util.Arrays;
+import java.util.Collections;
 import java.util.HashMap;
-import java.util.Iterator;
+import java.util.List;
 
-public class OlmAccount {
+public class OlmAccount implements AutoCloseable {
 
     private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
+    private static native void _free(final long ptr);
     private static native long _new();
     private static native IdentityKeys _identity_keys(final long sessionConfigPtr) throws OlmException;
-    private static native String _pickle(final long sessionConfigPtr, final String pickleKey) throws OlmException;
-    private static native long _from_pickle(final String pickle, final String pickleKey);
-    private static native long _from_pickle_lib_olm(final String pickle, final String pickleKey);
+    private static native String _pickle(final long sessionConfigPtr, final long pickleKey) throws OlmException;
+    private static native long _from_pickle(final String pickle, final long pickleKey) throws OlmException;
+    private static native String _pickle_lib_olm(final long ptr, final byte[] pickleKey) throws OlmException;
+    private static native long _from_pickle_lib_olm(final String pickle, final byte[] pickleKey) throws OlmException;
+    private static native DehydratedDevice _to_dehydrated_device(final long ptr, final byte[] key) throws OlmException;
+    private static native long _from_dehydrated_device(final String ciphertext, final String nonce,
+                                                       final byte[] key) throws OlmException;
     private static native String _ed25519_key(final long ptr);
     private static native String _curve25519Key(final long ptr);
     private static native String _sign(final long ptr, final String message);
+    private static native String _sign_json(final long ptr, final String json, final String userId,
+                                            final String deviceId) throws OlmException;
+    private static native String _keys_upload_body(final long ptr, final String userId, final String deviceId,
+                                                   final boolean includeDeviceKeys) throws OlmException;
     private static native long _maxNumberOfOneTimeKeys(final long ptr);
-    private static native String _oneTimeKeys(final long ptr) throws OlmException;
-    private static native String _fallbackKey(final long ptr) throws OlmException;
-    private static native void  _generateFallbackKey(final long ptr);
+    private static native OneTimeKey[] _one_time_keys(final long ptr) throws OlmException;
+    private static native OneTimeKey _fallback_key(final long ptr) throws OlmException;
+    private static native Curve25519PublicKey _generate_fallback_key(final long ptr) throws OlmException;
+    private static native boolean _forget_fallback_key(final long ptr);
+    private static native boolean _has_unpublished_fallback_key(final long ptr);
     private static native void  _markKeysAsPublished(final long ptr);
-    private static native void  _generateOneTimeKeys(final long ptr, final long size);
+    private static native OneTimeKeyGenerationResult _generate_one_time_keys(final long ptr,
+                                                                             final long size) throws OlmException;
     private static native long  _createOutboundSession(final long ptr, final String identityKey,
                                                        final String oneTimeKey,
-                                                       final long config);
+                                                       final long config) throws OlmException;
     private static native InboundCreationResult _createInboundSession(final long ptr,
                                                                       final String identityKey,
-                                                                      final String chiperText,
-                                                                      final long messageType) throws OlmException;
+                                                                      final long message) throws OlmException;
+    private static native InboundCreationBytes _createInboundSessionBytes(final long ptr,
+                                                                          final String identityKey,
+                                                                          final long message) throws OlmException;
+    private static native OlmDecryptionResult _decrypt_or_create_session(final long ptr, final String senderKey,
+                                                                         final long message,
+                                                                         final OlmSession[] sessions) throws OlmException;
 
     public OlmAccount() {
-        ptr = _new();
+        this(_new());
     }
 
     private OlmAccount(final long ptr) {
         this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, OlmAccount::_free);
     }
 
     public IdentityKeys identityKeys() throws OlmException {
         return _identity_keys(ptr);
     }
 
+    public String pickle(final PickleKey pickleKey) throws OlmException {
+        return _pickle(ptr, pickleKey.getPtr());
+    }
+
+    public String pickle(final byte[] pickleKey) throws OlmException {
+        try (PickleKey key = PickleKey.fromBytes(pickleKey)) {
+            return pickle(key);
+        }
+    }
+
+    /** @deprecated Use {@link #pickle(byte[])} with a key from a KDF or keystore. */
+    @Deprecated
     public String pickle(final String pickleKey) throws OlmException {
-        if (pickleKey == null || pickleKey.length() != 32) {
-            throw new OlmException("Pickle key must be 32 length");
+        return pickle(pickleKey == null ? null : pickleKey.getBytes(StandardCharsets.UTF_8));
+    }
+
+    public static OlmAccount fromPickle(final String pickle, final PickleKey pickleKey) throws OlmException {
+        final long ptr = _from_pickle(pickle, pickleKey.getPtr());
+        return new OlmAccount(ptr);
+    }
+
+    public static OlmAccount fromPickle(final String pickle, final byte[] pickleKey) throws OlmException {
+        try (PickleKey key = PickleKey.fromBytes(pickleKey)) {
+            return fromPickle(pickle, key);
         }
-        return _pickle(ptr, pickleKey);
     }
 
+    /** @deprecated Use {@link #fromPickle(String, byte[])} with a key from a KDF or keystore. */
+    @Deprecated
     public static OlmAccount fromPickle(final String pickle, final String pickleKey) throws OlmException {
-        if (pickleKey == null || pickleKey.length() != 32) {
-            throw new OlmException("Pickle key must be 32 length");
-        }
-        long ptr = _from_pickle(pickle, pickleKey);
+        return fromPickle(pickle, pickleKey == null ? null : pickleKey.getBytes(StandardCharsets.UTF_8));
+    }
+
+    /**
+     * Pickles the account in the libolm format, e.g. to hand it to a component
+     * that still uses libolm. Read it back with {@link #fromPickleLibOlm(String, byte[])}.
+     */
+    public String pickleLibOlm(final byte[] pickleKey) throws OlmException {
+        return _pickle_lib_olm(ptr, pickleKey);
+    }
+
+    public static OlmAccount fromPickleLibOlm(final String pickle, final byte[] pickleKey) throws OlmException {
+        final long ptr = _from_pickle_lib_olm(pickle, pickleKey);
         return new OlmAccount(ptr);
     }
 
-    public static OlmAccount fromPickleLibOlm(final String pickle, final String pickleKey) {
-        long ptr = _from_pickle_lib_olm(pickle, pickleKey);
+    /** @deprecated Use {@link #fromPickleLibOlm(String, byte[])}. */
+    @Deprecated
+    public static OlmAccount fromPickleLibOlm(final String pickle, final String pickleKey) throws OlmException {
+        return fromPickleLibOlm(pickle, pickleKey == null ? null : pickleKey.getBytes(StandardCharsets.UTF_8));
+    }
+
+    /**
+     * Encrypts this account as an MSC3814 dehydrated device with a 32 byte
+     * {@code key}. Only the keys are stored, so use a fresh account without sessions.
+     */
+    public DehydratedDevice toDehydratedDevice(final byte[] key) throws OlmException {
+        return _to_dehydrated_device(ptr, key);
+    }
+
+    /**
+     * Restores a dehydrated device created with {@link #toDehydratedDevice(byte[])}.
+     *
+     * @throws PickleException if the key is wrong or the device can't be decoded
+     */
+    public static OlmAccount fromDehydratedDevice(final String ciphertext, final String nonce,
+                                                  final byte[] key) throws OlmException {
+        final long ptr = _from_dehydrated_device(ciphertext, nonce, key);
         return new OlmAccount(ptr);
     }
 
+    public static OlmAccount fromDehydratedDevice(final DehydratedDevice device, final byte[] key) throws OlmException {
+        return fromDehydratedDevice(device.getCiphertext(), device.getNonce(), key);
+    }
+
     public String ed25519Key() {
         return _ed25519_key(ptr);
     }
@@ -75,50 +151,105 @@ public class OlmAccount {
         return _sign(ptr, message);
     }
 
+    /**
+     * Signs a JSON object in its Matrix canonical form, without its
+     * {@code signatures} and {@code unsigned} fields, and returns it with the
+     * signature added under {@code signatures.<userId>.ed25519:<deviceId>}.
+     */
+    public String signJson(final String json, final String userId, final String deviceId) throws OlmException {
+        return _sign_json(ptr, json, userId, deviceId);
+    }
+
+    /**
+     * Verifies a JSON object signed with {@link #signJson(String, String, String)}
+     * against the Ed25519 key of the signing device.
+     *
+     * @throws InvalidSignatureException if the signature is missing, malformed or doesn't match
+     */
+    public static void verifyJson(final String json, final String userId, final String deviceId,
+                                  final Ed25519PublicKey key) throws OlmException {
+        key.verifyJson(json, userId, deviceId);
+    }
+
+    /**
+     * Builds the JSON body of a {@code /keys/upload} request: the signed device
+     * keys if {@code includeDeviceKeys} is set, and the unpublished one-time and
+     * fallback keys as signed {@code signed_curve25519} key objects. Empty
+     * sections are left out.
+     *
+     * <p>Call {@link #markKeysAsPublished()} once the server accepted the upload.
+     */
+    public String keysUploadBody(final String userId, final String deviceId,
+                                 final boolean includeDeviceKeys) throws OlmException {
+        return _keys_upload_body(ptr, userId, deviceId, includeDeviceKeys);
+    }
+
     public long maxNumberOfOneTimeKeys() {
         return _maxNumberOfOneTimeKeys(ptr);
     }
 
+    /** The one-time keys that haven't been published yet, ordered by key id. */
+    public List<OneTimeKey> unpublishedOneTimeKeys() throws OlmException {
+        return Collections.unmodifiableList(Arrays.asList(_one_time_keys(ptr)));
+    }
+
+    /** @deprecated Use {@link #unpublishedOneTimeKeys()}. */
+    @Deprecated
     public HashMap<String, String> oneTimeKeys() throws OlmException {
-        final String res =  _oneTimeKeys(ptr);
-        try {
-            final JSONObject obj = new JSONObject(res);
-            final HashMap<String, String> map = new HashMap<String, String>();
-            final Iterator<String> keysItr = obj.keys();
-            while(keysItr.hasNext()) {
-                String key = keysItr.next();
-                map.put(key, obj.getString(key));
-            }
-            return map;
-        } catch (JSONException err) {
-            throw new OlmException(err);
-        }
+        return toKeyMap(unpublishedOneTimeKeys());
+    }
 
+    /**
+     * Generates {@code size} new one-time keys. If the account already holds as
+     * many keys as it keeps, the oldest ones are discarded and reported in the result.
+     */
+    public OneTimeKeyGenerationResult generateOneTimeKeys(final long size) throws OlmException {
+        return _generate_one_time_keys(ptr, size);
     }
 
-    public void generateOneTimeKeys(final long size) {
-        _generateOneTimeKeys(ptr, size);
+    /** The fallback key, or {@code null} if there is none or it was already published. */
+    public OneTimeKey unpublishedFallbackKey() throws OlmException {
+        return _fallback_key(ptr);
     }
 
+    /** @deprecated Use {@link #unpublishedFallbackKey()}. */
+    @Deprecated
     public HashMap<String, String> fallbackKey() throws OlmException {
-        final String res =  _fallbackKey(ptr);
-        try {
-            final JSONObject obj = new JSONObject(res);
-            final HashMap<String, String> map = new HashMap<String, String>();
-            final Iterator<String> keysItr = obj.keys();
-            while(keysItr.hasNext()) {
-                String key = keysItr.next();
-                map.put(key, obj.getString(key));
-            }
-            return map;
-        } catch (JSONException err) {
-            throw new OlmException(err);
+        final OneTimeKey key = unpublishedFallbackKey();
+        return toKeyMap(key == null ? Collections.<OneTimeKey>emptyList() : Collections.singletonList(key));
+    }
+
+    private static HashMap<String, String> toKeyMap(final List<OneTimeKey> keys) {
+        final HashMap<String, String> map = new HashMap<String, String>();
+        for (final OneTimeKey key : keys) {
+            map.put(key.getKeyId(), key.getKey().toBase64());
         }
+        return map;
+    }
+
+    /**
+     * Creates a new fallback key. The previous one is kept until
+     * {@link #forgetFallbackKey()}, the one before it is discarded and returned.
+     *
+     * @return the discarded key, or {@code null} if there was none
+     */
+    public Curve25519PublicKey generateFallbackKey() throws OlmException {
+        return _generate_fallback_key(ptr);
+    }
 
+    /**
+     * Discards the previous fallback key. Call this once a pre-key message for
+     * the current fallback key arrived.
+     *
+     * @return {@code false} if there was no previous fallback key
+     */
+    public boolean forgetFallbackKey() {
+        return _forget_fallback_key(ptr);
     }
 
-    public void generateFallbackKey() {
-        _generateFallbackKey(ptr);
+    /** Whether a fallback key exists that hasn't been published yet. */
+    public boolean hasUnpublishedFallbackKey() {
+        return _has_unpublished_fallback_key(ptr);
     }
 
     public void markKeysAsPublished() {
@@ -127,16 +258,49 @@ public class OlmAccount {
 
     public OlmSession createOutboundSession(final String identityKey,
                                             final String oneTimeKey,
-                                            final SessionConfig config) throws OlmException {
+                                            final OlmSessionConfig config) throws OlmException {
         long sessionPtr = _createOutboundSession(ptr, identityKey, oneTimeKey, config.getPtr());
         return new OlmSession(sessionPtr);
     }
 
-    public InboundCreationResult createInboundSession(final String identityKey, final OlmMessage message) throws OlmException{
-        try {
-            return _createInboundSession(ptr, identityKey, message.getCiphertext(), message.getMessageType());
-        } catch (Throwable t) {
-            throw new RuntimeException(t.getMessage());
-        }
+    /**
+     * Establishes the session a pre-key {@code message} from {@code identityKey}
+     * started and decrypts the message with it.
+     *
+     * @throws OlmSessionCreationException if the message doesn't start a session with our keys
+     * @throws OlmDecryptionException      if the message couldn't be decrypted
+     * @throws OlmException                if the plaintext isn't UTF-8, use
+     *                                     {@link #createInboundSessionBytes} for binary payloads
+     */
+    public InboundCreationResult createInboundSession(final String identityKey, final OlmMessage message) throws OlmException {
+        return _createInboundSession(ptr, identityKey, message.getPtr());
+    }
+
+    /**
+     * Like {@link #createInboundSession}, but returns the plaintext as is.
+     *
+     * @throws OlmSessionCreationException if the message doesn't start a session with our keys
+     * @throws OlmDecryptionException      if the message couldn't be decrypted
+     */
+    public InboundCreationBytes createInboundSessionBytes(final String identityKey, final OlmMessage message) throws OlmException {
+        return _createInboundSessionBytes(ptr, identityKey, message.getPtr());
+    }
+
+    /**
+     * Decrypts a message from {@code senderKey} with the first of the stored
+     * {@code sessions} with that sender that can, or else establishes a new
+     * session if it is a pre-key message.
+     *
+     * @throws OlmDecryptionException if no session could decrypt a normal
+     *         message, or the session a pre-key message belongs to couldn't
+     */
+    public OlmDecryptionResult decryptOrCreateSession(final String senderKey, final OlmMessage message,
+                                                      final List<OlmSession> sessions) throws OlmException {
+        return _decrypt_or_create_session(ptr, senderKey, message.getPtr(), sessions.toArray(new OlmSession[0]));
+    }
+
+    @Override
+    public void close() {
+        cleanable.clean();
     }
 }
@@ -0,0 +1,20 @@
+package de.cogia.vodozemac;
+
+public class OlmDecryptedBytes {
+
+    private final byte[] message;
+    private final long messageNumber;
+
+    public OlmDecryptedBytes(final byte[] message, final long messageNumber) {
+        this.message = message;
+        this.messageNumber = messageNumber;
+    }
+
+    public byte[] getMessage() {
+        return message;
+    }
+
+    public long getMessageNumber() {
+        return messageNumber;
+    }
+}
@@ -0,0 +1,8 @@
+package de.cogia.vodozemac;
+
+/** An Olm message couldn't be decrypted, e.g. because its MAC didn't match. */
+public class OlmDecryptionException extends OlmException {
+    public OlmDecryptionException(String message) {
+        super(message);
+    }
+}
@@ -0,0 +1,35 @@
+package de.cogia.vodozemac;
+
+import java.nio.charset.StandardCharsets;
+
+/** The outcome of {@link OlmAccount#decryptOrCreateSession}. */
+public class OlmDecryptionResult {
+
+    private final OlmSession session;
+    private final boolean newSession;
+    private final byte[] plaintext;
+
+    OlmDecryptionResult(final OlmSession session, final boolean newSession, final byte[] plaintext) {
+        this.session = session;
+        this.newSession = newSession;
+        this.plaintext = plaintext;
+    }
+
+    /** The session that decrypted the message, one of the candidates unless it is new. */
+    public OlmSession getSession() {
+        return session;
+    }
+
+    /** Whether the pre-key message established a new session, which has to be stored. */
+    public boolean isNewSession() {
+        return newSession;
+    }
+
+    public byte[] getPlaintext() {
+        return plaintext;
+    }
+
+    public String getPlaintextString() {
+        return new String(plaintext, StandardCharsets.UTF_8);
+    }
+}
@@ -1,17 +1,22 @@
 package de.cogia.vodozemac;
 
-public class OlmEstablishedSas {
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+public class OlmEstablishedSas implements AutoCloseable {
 
     private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
 
+    private static native void _free(final long ptr);
     private static native long _bytes(final long ptr, final String info);
     private static native String _calculate_mac(final long ptr, final String input, final String info);
     private static native String _calculate_mac_invalid_base64(final long ptr, final String input, final String info);
-    private static native boolean _verify_mac(final long ptr, final String input, final String info, final String tag);
+    private static native boolean _verify_mac(final long ptr, final String input, final String info, final String tag) throws OlmException;
 
 
     public OlmEstablishedSas(final long ptr) {
         this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, OlmEstablishedSas::_free);
     }
 
     public OlmSasBytes bytes(final String info) {
@@ -27,8 +32,12 @@ public class OlmEstablishedSas {
         return _calculate_mac_invalid_base64(ptr, input, info);
     }
 
-    public boolean verifyMac(final String input, final String info, final String tag) {
+    public boolean verifyMac(final String input, final String info, final String tag) throws OlmException {
         return _verify_mac(ptr, input, info, tag);
     }
 
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
 }
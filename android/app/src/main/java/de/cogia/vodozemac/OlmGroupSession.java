@@ -1,22 +1,42 @@
 package de.cogia.vodozemac;
 
-public class OlmGroupSession {
+import java.nio.ByteBuffer;
+import java.nio.charset.StandardCharsets;
+
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+public class OlmGroupSession implements AutoCloseable {
     private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
 
+    private static native void _free(final long ptr);
     private static native long _new(final long ptr);
     private static native String _session_id(final long ptr);
     private static native String _session_key(final long ptr);
-    private static native String _message_key(final long ptr);
-    private static native String _encrypt(final long ptr, final String message);
-    private static native String _pickle(final long ptr, final String passPhrase) throws OlmException;
-    private static native long _from_pickle(final String pickle, final String passPhrase);
+    private static native long _session_config(final long ptr);
+    private static native long _message_index(final long ptr);
+    private static native MegolmMessage _encrypt(final long ptr, final String message);
+    private static native MegolmMessage _encrypt_bytes(final long ptr, final byte[] message);
+    private static native MegolmMessage _encrypt_buffer(final long ptr, final ByteBuffer message,
+                                                        final int offset, final int length) throws OlmException;
+    private static native String _encrypt_room_event(final long ptr, final String roomId, final String type,
+                                                     final String content, final String senderKey,
+                                                     final String deviceId) throws OlmException;
+    private static native String _pickle(final long ptr, final long pickleKey) throws OlmException;
+    private static native long _from_pickle(final String pickle, final long pickleKey) throws OlmException;
+    private static native long _from_libolm_pickle(final String pickle, final byte[] passPhrase) throws OlmException;
 
-    public OlmGroupSession(final SessionConfig config) {
-        ptr = _new(config.getPtr());
+    public OlmGroupSession(final MegolmSessionConfig config) {
+        this(_new(config.getPtr()));
     }
 
     private OlmGroupSession(final long ptr) {
         this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, OlmGroupSession::_free);
+    }
+
+    long getPtr() {
+        return ptr;
     }
 
     public String sessionId() {
@@ -27,27 +47,85 @@ public class OlmGroupSession {
         return _session_key(ptr);
     }
 
-    public String messageKey() {
-        return _message_key(ptr);
+    public MegolmSessionConfig sessionConfig() {
+        return new MegolmSessionConfig(_session_config(ptr));
     }
 
-    public String encrypt(final String message) {
+    /** The index the next encrypted message will get. */
+    public long messageIndex() {
+        return _message_index(ptr);
+    }
+
+    public MegolmMessage encrypt(final String message) {
         return _encrypt(ptr, message);
     }
 
+    public MegolmMessage encrypt(final byte[] message) {
+        return _encrypt_bytes(ptr, message);
+    }
+
+    /**
+     * Encrypts the remaining bytes of a direct buffer, the buffer's position
+     * is advanced to its limit.
+     */
+    public MegolmMessage encrypt(final ByteBuffer message) throws OlmException {
+        return _encrypt_buffer(ptr, message, message.position(), message.remaining());
+    }
+
+    /**
+     * Encrypts an event of {@code type} for {@code roomId}.
+     *
+     * @param content   the event content as a JSON object
+     * @param senderKey the Curve25519 key of our account
+     * @param deviceId  the id of our device
+     * @return the content of the {@code m.room.encrypted} event to send, as a JSON object
+     */
+    public String encryptRoomEvent(final String roomId, final String type, final String content,
+                                   final String senderKey, final String deviceId) throws OlmException {
+        return _encrypt_room_event(ptr, roomId, type, content, senderKey, deviceId);
+    }
+
+    public String pickle(final PickleKey pickleKey) throws OlmException {
+        return _pickle(ptr, pickleKey.getPtr());
+    }
+
+    public String pickle(final byte[] passPhrase) throws OlmException {
+        try (PickleKey key = PickleKey.fromBytes(passPhrase)) {
+            return pickle(key);
+        }
+    }
+
+    /** @deprecated Use {@link #pickle(byte[])} with a key from a KDF or keystore. */
+    @Deprecated
     public String pickle(final String passPhrase) throws OlmException {
-        if (passPhrase == null || passPhrase.length() != 32) {
-            throw new OlmException("Pickle key must be 32 length");
+        return pickle(passPhrase == null ? null : passPhrase.getBytes(StandardCharsets.UTF_8));
+    }
+
+    public static OlmGroupSession fromPickle(final String pickle, final PickleKey pickleKey) throws OlmException {
+        final long ptr = _from_pickle(pickle, pickleKey.getPtr());
+        return new OlmGroupSession(ptr);
+    }
+
+    public static OlmGroupSession fromPickle(final String pickle, final byte[] passPhrase) throws OlmException {
+        try (PickleKey key = PickleKey.fromBytes(passPhrase)) {
+            return fromPickle(pickle, key);
         }
-        return  _pickle(ptr, passPhrase);
     }
 
+    /** @deprecated Use {@link #fromPickle(String, byte[])} with a key from a KDF or keystore. */
+    @Deprecated
     public static OlmGroupSession fromPickle(final String pickle, final String passPhrase) throws OlmException {
-        if (passPhrase == null || passPhrase.length() != 32) {
-            throw new OlmException("Pickle key must be 32 length");
-        }
-        final long ptr = _from_pickle(pickle, passPhrase);
+        return fromPickle(pickle, passPhrase == null ? null : passPhrase.getBytes(StandardCharsets.UTF_8));
+    }
+
+    /** Imports an outbound session pickled by libolm, it uses Megolm v1. */
+    public static OlmGroupSession fromLibOlmPickle(final String pickle, final byte[] passPhrase) throws OlmException {
+        final long ptr = _from_libolm_pickle(pickle, passPhrase);
         return new OlmGroupSession(ptr);
     }
 
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
 }
@@ -1,40 +1,92 @@
 package de.cogia.vodozemac;
 
-public class OlmInboundGroupSession {
+import java.nio.ByteBuffer;
+import java.nio.charset.StandardCharsets;
+
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+public class OlmInboundGroupSession implements AutoCloseable {
 
     private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
 
-    private static native long _new(final String sessionKey, final long ptr);
+    private static native void _free(final long ptr);
+    private static native long _new(final String sessionKey, final long ptr) throws OlmException;
+    private static native long _from_group_session(final long groupSession);
     private static native long _import(final String sessionKey, final long ptr) throws OlmException;
-    private static native String _pickle(final long ptr, final String passPhrase) throws OlmException;
-    private static native long _from_pickle(final String pickle, final String passPhrase);
-    private static native long _from_libolm_pickle(final String pickle, final String passPhrase);
+    private static native String _pickle(final long ptr, final long pickleKey) throws OlmException;
+    private static native long _from_pickle(final String pickle, final long pickleKey) throws OlmException;
+    private static native long _from_libolm_pickle(final String pickle, final byte[] passPhrase) throws OlmException;
     private static native String _session_id(final long ptr);
     private static native long _first_known_index(final long ptr);
-    private static native String _export_at(final long ptr, final long index);
+    private static native long _session_config(final long ptr);
+    private static native boolean _connected(final long ptr, final long other);
+    private static native int _compare(final long ptr, final long other);
+    private static native long _merge(final long ptr, final long other);
+    private static native String _export_at(final long ptr, final long index) throws OlmException;
     private static native OlmDecryptedMessage _decrypt(final long ptr, final String cipertext) throws OlmException;
+    private static native OlmDecryptedBytes _decrypt_bytes(final long ptr, final String cipertext) throws OlmException;
+    private static native long _decrypt_buffer(final long ptr, final String cipertext, final ByteBuffer buffer,
+                                               final int offset, final int length) throws OlmException;
+
+    private static native DecryptedRoomEvent _decrypt_room_event(final long ptr, final String roomId,
+                                                                 final String encryptedContent, final long guard,
+                                                                 final String eventId,
+                                                                 final long timestamp) throws OlmException;
 
-    public OlmInboundGroupSession(final String sessionKey, final SessionConfig config) {
-        this.ptr = _new(sessionKey, config.getPtr());
+    public OlmInboundGroupSession(final String sessionKey, final MegolmSessionConfig config) throws OlmException {
+        this(_new(sessionKey, config.getPtr()));
     }
 
     private OlmInboundGroupSession(final long ptr) {
         this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, OlmInboundGroupSession::_free);
     }
 
+    /**
+     * The inbound session our own device needs to decrypt what {@code session}
+     * encrypts from its current message index on, without exporting the
+     * session key.
+     */
+    public static OlmInboundGroupSession fromGroupSession(final OlmGroupSession session) {
+        return new OlmInboundGroupSession(_from_group_session(session.getPtr()));
+    }
+
+    long getPtr() {
+        return ptr;
+    }
+
+    public String pickle(final PickleKey pickleKey) throws OlmException {
+        return _pickle(ptr, pickleKey.getPtr());
+    }
+
+    public String pickle(final byte[] passPhrase) throws OlmException {
+        try (PickleKey key = PickleKey.fromBytes(passPhrase)) {
+            return pickle(key);
+        }
+    }
+
+    /** @deprecated Use {@link #pickle(byte[])} with a key from a KDF or keystore. */
+    @Deprecated
     public String pickle(final String passPhrase) throws OlmException {
-        if (passPhrase == null || passPhrase.length() != 32) {
-            throw new OlmException("Pickle key must be 32 length");
+        return pickle(passPhrase == null ? null : passPhrase.getBytes(StandardCharsets.UTF_8));
+    }
+
+    public static OlmInboundGroupSession fromPickle(final String pickle, final PickleKey pickleKey) throws OlmException {
+        final long ptr = _from_pickle(pickle, pickleKey.getPtr());
+        return new OlmInboundGroupSession(ptr);
+    }
+
+    public static OlmInboundGroupSession fromPickle(final String pickle, final byte[] passPhrase) throws OlmException {
+        try (PickleKey key = PickleKey.fromBytes(passPhrase)) {
+            return fromPickle(pickle, key);
         }
-        return  _pickle(ptr, passPhrase);
     }
 
+    /** @deprecated Use {@link #fromPickle(String, byte[])} with a key from a KDF or keystore. */
+    @Deprecated
     public static OlmInboundGroupSession fromPickle(final String pickle, final String passPhrase) throws OlmException {
-        if (passPhrase == null || passPhrase.length() != 32) {
-            throw new OlmException("Pickle key must be 32 length");
-        }
-        final long ptr = _from_pickle(pickle, passPhrase);
-        return new OlmInboundGroupSession(ptr);
+        return fromPickle(pickle, passPhrase == null ? null : passPhrase.getBytes(StandardCharsets.UTF_8));
     }
 
     public String sessionId() {
@@ -45,24 +97,101 @@ public class OlmInboundGroupSession {
         return _first_known_index(ptr);
     }
 
-    public static OlmInboundGroupSession importFrom(final String sessionKey, final SessionConfig config) throws OlmException {
+    public MegolmSessionConfig sessionConfig() {
+        return new MegolmSessionConfig(_session_config(ptr));
+    }
+
+    public static OlmInboundGroupSession importFrom(final String sessionKey, final MegolmSessionConfig config) throws OlmException {
         final long ptr = _import(sessionKey, config.getPtr());
         return new OlmInboundGroupSession(ptr);
     }
 
-    public String exportAt(final long index) {
+    /**
+     * Whether both sessions were created from the same {@link OlmGroupSession},
+     * possibly at different message indices.
+     */
+    public boolean connected(final OlmInboundGroupSession other) {
+        return _connected(ptr, other.ptr);
+    }
+
+    /**
+     * Decides which of two copies of a room key to keep: a {@link SessionOrdering#BETTER}
+     * session can decrypt older messages, an {@link SessionOrdering#UNCONNECTED}
+     * one must not replace the other.
+     */
+    public SessionOrdering compare(final OlmInboundGroupSession other) {
+        return SessionOrdering.fromCode(_compare(ptr, other.ptr));
+    }
+
+    /**
+     * Combines the lower first known index of the two sessions with the better
+     * trust of the two.
+     *
+     * @return the merged session, or null if the sessions aren't connected
+     */
+    public OlmInboundGroupSession merge(final OlmInboundGroupSession other) {
+        final long merged = _merge(ptr, other.ptr);
+        return merged == 0 ? null : new OlmInboundGroupSession(merged);
+    }
+
+    public String exportAt(final long index) throws OlmException {
         return _export_at(ptr, index);
     }
 
-    public static OlmInboundGroupSession fromLibOlmPickle(final String pickle, final String passPhrase) throws OlmException {
-        if (passPhrase == null || passPhrase.length() != 32) {
-            throw new OlmException("Pickle key must be 32 length");
-        }
-        final long ptr =  _from_libolm_pickle(pickle, passPhrase);
+    public static OlmInboundGroupSession fromLibOlmPickle(final String pickle, final byte[] passPhrase) throws OlmException {
+        final long ptr = _from_libolm_pickle(pickle, passPhrase);
         return new OlmInboundGroupSession(ptr);
     }
 
+    /** @deprecated Use {@link #fromLibOlmPickle(String, byte[])}. */
+    @Deprecated
+    public static OlmInboundGroupSession fromLibOlmPickle(final String pickle, final String passPhrase) throws OlmException {
+        return fromLibOlmPickle(pickle, passPhrase == null ? null : passPhrase.getBytes(StandardCharsets.UTF_8));
+    }
+
     public OlmDecryptedMessage decrypt(final String cipertext) throws OlmException {
         return _decrypt(ptr, cipertext);
     }
+
+    public OlmDecryptedBytes decryptBytes(final String cipertext) throws OlmException {
+        return _decrypt_bytes(ptr, cipertext);
+    }
+
+    /**
+     * Decrypts into the remaining space of a direct buffer and advances its
+     * position past the plaintext.
+     *
+     * @return the message index of the decrypted message
+     */
+    public long decrypt(final String cipertext, final ByteBuffer output) throws OlmException {
+        return _decrypt_buffer(ptr, cipertext, output, output.position(), output.remaining());
+    }
+
+    /**
+     * Decrypts the content of an {@code m.room.encrypted} event received in
+     * {@code roomId}.
+     *
+     * @throws RoomIdMismatchException if the event was encrypted for another room
+     */
+    public DecryptedRoomEvent decryptRoomEvent(final String roomId, final String encryptedContent) throws OlmException {
+        return _decrypt_room_event(ptr, roomId, encryptedContent, 0, null, 0);
+    }
+
+    /**
+     * Decrypts the content of the event {@code eventId} and records its
+     * message index in {@code guard}.
+     *
+     * @throws MegolmReplayException if the message index was already used by another event
+     * @see MegolmReplayGuard#decrypt(OlmInboundGroupSession, String, String, long)
+     */
+    public DecryptedRoomEvent decryptRoomEvent(final String roomId, final String encryptedContent,
+                                               final MegolmReplayGuard guard, final String eventId,
+                                               final long timestamp) throws OlmException {
+        return _decrypt_room_event(ptr, roomId, encryptedContent, guard.getPtr(), eventId, timestamp);
+    }
+
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
 }
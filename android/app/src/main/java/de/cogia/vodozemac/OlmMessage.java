@@ -1,20 +1,96 @@
 package de.cogia.vodozemac;
 
-public class OlmMessage {
+import de.cogia.vodozemac.internal.NativeCleaner;
 
-    private final long messageType;
-    private final String ciphertext;
+/**
+ * An Olm message. It is decoded once when it is created, a malformed message
+ * is rejected right away instead of by every call it is passed to.
+ */
+public class OlmMessage implements AutoCloseable {
 
-    public OlmMessage(final String ciphertext, final long message_type) {
-        this.ciphertext = ciphertext;
-        this.messageType = message_type;
+    /** The message types, the codes match the {@code type} field of an Olm ciphertext. */
+    public enum Type {
+        PRE_KEY(0),
+        NORMAL(1);
+
+        private final int code;
+
+        Type(final int code) {
+            this.code = code;
+        }
+
+        public int getCode() {
+            return code;
+        }
+
+        static Type fromCode(final int code) {
+            for (Type type : values()) {
+                if (type.code == code) {
+                    return type;
+                }
+            }
+            throw new IllegalArgumentException("Unknown Olm message type " + code);
+        }
     }
 
-    public String getCiphertext() {
-        return ciphertext;
+    private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
+
+    private static native void _free(final long ptr);
+    private static native long _from_parts(final int type, final String ciphertext) throws OlmException;
+    private static native int _message_type(final long ptr);
+    private static native String _ciphertext(final long ptr);
+    private static native OlmSessionKeys _session_keys(final long ptr);
+
+    OlmMessage(final long ptr) {
+        this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, OlmMessage::_free);
+    }
+
+    /** Decodes the unpadded base64 {@code ciphertext} of a message of the given type. */
+    public static OlmMessage fromParts(final Type type, final String ciphertext) throws OlmException {
+        return new OlmMessage(_from_parts(type.code, ciphertext));
+    }
+
+    /** @deprecated Use {@link #fromParts(Type, String)}. */
+    @Deprecated
+    public static OlmMessage fromParts(final long messageType, final String ciphertext) throws OlmException {
+        if (messageType < Integer.MIN_VALUE || messageType > Integer.MAX_VALUE) {
+            throw new IllegalArgumentException("Unknown Olm message type " + messageType);
+        }
+        return fromParts(Type.fromCode((int) messageType), ciphertext);
     }
 
+    long getPtr() {
+        return ptr;
+    }
+
+    public Type getType() {
+        return Type.fromCode(_message_type(ptr));
+    }
+
+    /** @deprecated Use {@link #getType()}. */
+    @Deprecated
     public long getMessageType() {
-        return messageType;
+        return getType().code;
+    }
+
+    public String getCiphertext() {
+        return _ciphertext(ptr);
+    }
+
+    /**
+     * The keys of the session a pre-key message wants to establish, they tell
+     * which account and session the message is meant for before decrypting it.
+     *
+     * @return the keys, or null for a normal message
+     */
+    public OlmSessionKeys getSessionKeys() {
+        return _session_keys(ptr);
+    }
+
+    @Override
+    public void close() {
+        cleanable.clean();
     }
 }
@@ -1,18 +1,23 @@
 package de.cogia.vodozemac;
 
-public class OlmSas {
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+public class OlmSas implements AutoCloseable {
 
     private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
+    private static native void _free(final long ptr);
     private static native long _new();
-    private static native String _public_key();
+    private static native String _public_key(final long ptr) throws OlmException;
     private static native long _diffie_hellman(final long ptr, final String key) throws OlmException;
 
     public OlmSas() {
-        ptr =_new();
+        ptr = _new();
+        cleanable = NativeCleaner.register(this, ptr, OlmSas::_free);
     }
 
-    public String publicKey() {
-        return _public_key();
+    public String publicKey() throws OlmException {
+        return _public_key(ptr);
     }
 
     public OlmEstablishedSas diffie_hellman(final String key) throws OlmException {
@@ -22,4 +27,9 @@ public class OlmSas {
         }
         return new OlmEstablishedSas(res);
     }
+
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
 }
@@ -1,13 +1,18 @@
 package de.cogia.vodozemac;
 
-public class OlmSasBytes {
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+public class OlmSasBytes implements AutoCloseable {
 
     private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
+    private static native void _free(final long ptr);
     private static native long[] _emoji_indices(final long ptr);
     private static native long[] _decimals(final long ptr);
 
     public OlmSasBytes(final long ptr) {
         this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, OlmSasBytes::_free);
     }
 
     public long[] decimals() {
@@ -18,4 +23,8 @@ public class OlmSasBytes {
         return _emoji_indices(ptr);
     }
 
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
 }
@@ -1,55 +1,164 @@
 package de.cogia.vodozemac;
 
-public class OlmSession {
+import java.nio.ByteBuffer;
+import java.nio.charset.StandardCharsets;
+
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+public class OlmSession implements AutoCloseable {
 
     private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
 
-    private static native String _pickle(final long sessionConfigPtr, final String pickleKey) throws OlmException;
-    private static native long _from_pickle(final String pickle, final String pickleKey);
-    private static native long _from_pickle_lib_olm(final String pickle, final String pickleKey);
+    private static native void _free(final long ptr);
+    private static native String _pickle(final long sessionConfigPtr, final long pickleKey) throws OlmException;
+    private static native long _from_pickle(final String pickle, final long pickleKey) throws OlmException;
+    private static native long _from_pickle_lib_olm(final String pickle, final byte[] pickleKey) throws OlmException;
     private static native String _session_id(final long ptr);
-    private static native boolean _session_matches(final long ptr, final String text, final long type);
-    private static native String _decrypt(final long ptr,  final String text, final long type) throws OlmException;
+    private static native long _session_config(final long ptr);
+    private static native boolean _has_received_message(final long ptr);
+    private static native OlmSessionKeys _session_keys(final long ptr);
+    private static native String _describe(final long ptr);
+    private static native boolean _session_matches(final long ptr, final long message);
+    private static native String _decrypt(final long ptr, final long message) throws OlmException;
     private static native OlmMessage _encrypt(final long ptr,  final String text);
+    private static native byte[] _decrypt_bytes(final long ptr, final long message) throws OlmException;
+    private static native int _decrypt_buffer(final long ptr, final long message, final ByteBuffer buffer,
+                                              final int offset, final int length) throws OlmException;
+    private static native OlmMessage _encrypt_bytes(final long ptr, final byte[] plaintext);
+    private static native OlmMessage _encrypt_buffer(final long ptr, final ByteBuffer plaintext,
+                                                     final int offset, final int length) throws OlmException;
 
 
     public OlmSession(final long ptr) {
         this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, OlmSession::_free);
     }
 
-    public String pickle(final String pickleKey) throws OlmException {
-        if (pickleKey == null || pickleKey.length() != 32) {
-            throw new OlmException("Pickle key must be 32 length");
-        }
-        return _pickle(ptr, pickleKey);
+    long getPtr() {
+        return ptr;
     }
 
-    public static OlmSession fromPickle(final String pickle, final String pickleKey) throws Exception {
-        if (pickleKey == null || pickleKey.length() != 32) {
-            throw new OlmException("Pickle key must be 32 length");
+    public String pickle(final PickleKey pickleKey) throws OlmException {
+        return _pickle(ptr, pickleKey.getPtr());
+    }
+
+    public String pickle(final byte[] pickleKey) throws OlmException {
+        try (PickleKey key = PickleKey.fromBytes(pickleKey)) {
+            return pickle(key);
         }
-        long ptr = _from_pickle(pickle, pickleKey);
+    }
+
+    /** @deprecated Use {@link #pickle(byte[])} with a key from a KDF or keystore. */
+    @Deprecated
+    public String pickle(final String pickleKey) throws OlmException {
+        return pickle(pickleKey == null ? null : pickleKey.getBytes(StandardCharsets.UTF_8));
+    }
+
+    public static OlmSession fromPickle(final String pickle, final PickleKey pickleKey) throws OlmException {
+        final long ptr = _from_pickle(pickle, pickleKey.getPtr());
         return new OlmSession(ptr);
     }
 
-    public static OlmSession fromPickleLibOlm(final String pickle, final String pickleKey) {
-        long ptr = _from_pickle_lib_olm(pickle, pickleKey);
+    public static OlmSession fromPickle(final String pickle, final byte[] pickleKey) throws OlmException {
+        try (PickleKey key = PickleKey.fromBytes(pickleKey)) {
+            return fromPickle(pickle, key);
+        }
+    }
+
+    /** @deprecated Use {@link #fromPickle(String, byte[])} with a key from a KDF or keystore. */
+    @Deprecated
+    public static OlmSession fromPickle(final String pickle, final String pickleKey) throws OlmException {
+        return fromPickle(pickle, pickleKey == null ? null : pickleKey.getBytes(StandardCharsets.UTF_8));
+    }
+
+    /**
+     * Imports a session pickled by libolm. There is no export the other way,
+     * vodozemac can't write libolm session pickles.
+     */
+    public static OlmSession fromPickleLibOlm(final String pickle, final byte[] pickleKey) throws OlmException {
+        final long ptr = _from_pickle_lib_olm(pickle, pickleKey);
         return new OlmSession(ptr);
     }
 
+    /** @deprecated Use {@link #fromPickleLibOlm(String, byte[])}. */
+    @Deprecated
+    public static OlmSession fromPickleLibOlm(final String pickle, final String pickleKey) throws OlmException {
+        return fromPickleLibOlm(pickle, pickleKey == null ? null : pickleKey.getBytes(StandardCharsets.UTF_8));
+    }
+
     public String sessionId() {
         return _session_id(ptr);
     }
 
+    public OlmSessionConfig sessionConfig() {
+        return new OlmSessionConfig(_session_config(ptr));
+    }
+
+    /**
+     * Whether a message from the other side was decrypted yet, until then
+     * {@link #encrypt(String)} keeps producing pre-key messages.
+     */
+    public boolean hasReceivedMessage() {
+        return _has_received_message(ptr);
+    }
+
+    public OlmSessionKeys sessionKeys() {
+        return _session_keys(ptr);
+    }
+
+    /**
+     * A summary of the ratchet state for logs. It holds chain indices and
+     * public ratchet keys, but no secrets.
+     */
+    public String describe() {
+        return _describe(ptr);
+    }
+
     public boolean sessionMatches(final OlmMessage message) {
-        return _session_matches(ptr, message.getCiphertext(), message.getMessageType());
+        return _session_matches(ptr, message.getPtr());
     }
 
     public String decrypt(final OlmMessage message) throws OlmException {
-        return _decrypt(ptr, message.getCiphertext(), message.getMessageType());
+        return _decrypt(ptr, message.getPtr());
+    }
+
+    public byte[] decryptBytes(final OlmMessage message) throws OlmException {
+        return _decrypt_bytes(ptr, message.getPtr());
+    }
+
+    /**
+     * Decrypts into the remaining space of a direct buffer and advances its
+     * position past the plaintext.
+     * <p>
+     * The remaining space has to fit the padded plaintext, at most 16 bytes
+     * more than the plaintext itself. A buffer that is too small is rejected
+     * before anything is decrypted, so the message can be decrypted again.
+     *
+     * @return the length of the plaintext
+     */
+    public int decrypt(final OlmMessage message, final ByteBuffer output) throws OlmException {
+        return _decrypt_buffer(ptr, message.getPtr(), output, output.position(), output.remaining());
     }
 
     public OlmMessage encrypt(final String message) {
         return _encrypt(ptr, message);
     }
+
+    public OlmMessage encrypt(final byte[] plaintext) {
+        return _encrypt_bytes(ptr, plaintext);
+    }
+
+    /**
+     * Encrypts the remaining bytes of a direct buffer, the buffer's position
+     * is advanced to its limit.
+     */
+    public OlmMessage encrypt(final ByteBuffer plaintext) throws OlmException {
+        return _encrypt_buffer(ptr, plaintext, plaintext.position(), plaintext.remaining());
+    }
+
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
 }
@@ -0,0 +1,41 @@
+package de.cogia.vodozemac;
+
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+public class OlmSessionConfig implements AutoCloseable {
+
+    private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
+    private static native void _free(final long ptr);
+    private static native long _version1();
+    private static native long _version2();
+    private static native long _version(long sessionConfigPtr);
+
+    OlmSessionConfig(long ptr) {
+        this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, OlmSessionConfig::_free);
+    }
+
+    public long getPtr() {
+        return ptr;
+    }
+
+    public static OlmSessionConfig version1() {
+        long ptr = _version1();
+        return new OlmSessionConfig(ptr);
+    }
+
+    public static OlmSessionConfig version2() {
+        long ptr = _version2();
+        return new OlmSessionConfig(ptr);
+    }
+
+    public long version() {
+        return _version(ptr);
+    }
+
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
+}
@@ -0,0 +1,8 @@
+package de.cogia.vodozemac;
+
+/** An inbound Olm session couldn't be created from a pre-key message. */
+public class OlmSessionCreationException extends OlmException {
+    public OlmSessionCreationException(String message) {
+        super(message);
+    }
+}
@@ -0,0 +1,31 @@
+package de.cogia.vodozemac;
+
+/** The keys an {@link OlmSession} was established with. */
+public class OlmSessionKeys {
+
+    private final Curve25519PublicKey identityKey;
+    private final Curve25519PublicKey baseKey;
+    private final Curve25519PublicKey oneTimeKey;
+
+    OlmSessionKeys(final Curve25519PublicKey identityKey, final Curve25519PublicKey baseKey,
+                   final Curve25519PublicKey oneTimeKey) {
+        this.identityKey = identityKey;
+        this.baseKey = baseKey;
+        this.oneTimeKey = oneTimeKey;
+    }
+
+    /** The identity key of the account that created the outbound session. */
+    public Curve25519PublicKey getIdentityKey() {
+        return identityKey;
+    }
+
+    /** The ephemeral key the outbound side generated for this session. */
+    public Curve25519PublicKey getBaseKey() {
+        return baseKey;
+    }
+
+    /** The one-time or fallback key of the inbound side that was used up. */
+    public Curve25519PublicKey getOneTimeKey() {
+        return oneTimeKey;
+    }
+}
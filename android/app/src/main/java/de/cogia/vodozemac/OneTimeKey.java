@@ -0,0 +1,22 @@
+package de.cogia.vodozemac;
+
+/** An unpublished one-time or fallback key of an {@link OlmAccount}. */
+public class OneTimeKey {
+
+    private final String keyId;
+    private final Curve25519PublicKey key;
+
+    OneTimeKey(final String keyId, final Curve25519PublicKey key) {
+        this.keyId = keyId;
+        this.key = key;
+    }
+
+    /** The unpadded base64 key id, as used in {@code signed_curve25519:<keyId>}. */
+    public String getKeyId() {
+        return keyId;
+    }
+
+    public Curve25519PublicKey getKey() {
+        return key;
+    }
+}
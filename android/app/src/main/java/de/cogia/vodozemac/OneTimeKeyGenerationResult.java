@@ -0,0 +1,29 @@
+package de.cogia.vodozemac;
+
+import java.util.Arrays;
+import java.util.Collections;
+import java.util.List;
+
+/**
+ * The one-time keys {@link OlmAccount#generateOneTimeKeys(long)} created, and the
+ * oldest keys it discarded to make room for them. Pre-key messages for a
+ * discarded key can't be decrypted anymore.
+ */
+public class OneTimeKeyGenerationResult {
+
+    private final List<OneTimeKey> created;
+    private final List<Curve25519PublicKey> removed;
+
+    OneTimeKeyGenerationResult(final OneTimeKey[] created, final Curve25519PublicKey[] removed) {
+        this.created = Collections.unmodifiableList(Arrays.asList(created));
+        this.removed = Collections.unmodifiableList(Arrays.asList(removed));
+    }
+
+    public List<OneTimeKey> getCreated() {
+        return created;
+    }
+
+    public List<Curve25519PublicKey> getRemoved() {
+        return removed;
+    }
+}
@@ -0,0 +1,8 @@
+package de.cogia.vodozemac;
+
+/** A pickle couldn't be created or decrypted, e.g. because the pickle key is wrong. */
+public class PickleException extends OlmException {
+    public PickleException(String message) {
+        super(message);
+    }
+}
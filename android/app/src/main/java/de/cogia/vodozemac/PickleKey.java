@@ -0,0 +1,79 @@
+package de.cogia.vodozemac;
+
+import de.cogia.vodozemac.internal.NativeCleaner;
+
+/**
+ * The 32 byte key pickles are encrypted with.
+ * <p>
+ * A key derived from a passphrase carries a header with the KDF parameters and
+ * salt. The header isn't secret: store it next to the pickles and pass it to
+ * {@link #fromPassphrase(byte[], byte[])} to derive the same key again.
+ */
+public class PickleKey implements AutoCloseable {
+
+    private final long ptr;
+    private final NativeCleaner.Cleanable cleanable;
+
+    private static native void _free(final long ptr);
+    private static native long _from_bytes(final byte[] key) throws OlmException;
+    private static native long _derive_pbkdf2(final byte[] passphrase, final int iterations) throws OlmException;
+    private static native long _derive_argon2id(final byte[] passphrase, final int memoryKib,
+                                                final int iterations, final int parallelism) throws OlmException;
+    private static native long _from_passphrase(final byte[] passphrase, final byte[] header) throws OlmException;
+    private static native byte[] _header(final long ptr);
+
+    private PickleKey(final long ptr) {
+        this.ptr = ptr;
+        this.cleanable = NativeCleaner.register(this, ptr, PickleKey::_free);
+    }
+
+    long getPtr() {
+        return ptr;
+    }
+
+    /** Uses {@code key} as is, it must be exactly 32 bytes long. */
+    public static PickleKey fromBytes(final byte[] key) throws OlmException {
+        return new PickleKey(_from_bytes(key));
+    }
+
+    /**
+     * Derives a new key with PBKDF2-HMAC-SHA512 and a random salt.
+     *
+     * @param iterations between 1 and 10,000,000
+     */
+    public static PickleKey derivePbkdf2(final byte[] passphrase, final int iterations) throws OlmException {
+        return new PickleKey(_derive_pbkdf2(passphrase, iterations));
+    }
+
+    /**
+     * Derives a new key with Argon2id and a random salt.
+     *
+     * @param memoryKib   between 8 and 262,144 (256 MiB), at least 8 per lane
+     * @param iterations  between 1 and 64
+     * @param parallelism between 1 and 16
+     */
+    public static PickleKey deriveArgon2id(final byte[] passphrase, final int memoryKib,
+                                           final int iterations, final int parallelism) throws OlmException {
+        return new PickleKey(_derive_argon2id(passphrase, memoryKib, iterations, parallelism));
+    }
+
+    /**
+     * Derives the key described by {@code header} again.
+     *
+     * @throws PickleException if the header is malformed or asks for KDF
+     *                         parameters outside the bounds of the derive methods
+     */
+    public static PickleKey fromPassphrase(final byte[] passphrase, final byte[] header) throws OlmException {
+        return new PickleKey(_from_passphrase(passphrase, header));
+    }
+
+    /** The derivation header, {@code null} for keys created with {@link #fromBytes(byte[])}. */
+    public byte[] getHeader() {
+        return _header(ptr);
+    }
+
+    @Override
+    public void close() {
+        cleanable.clean();
+    }
+}
@@ -0,0 +1,26 @@
+package de.cogia.vodozemac;
+
+/**
+ * An encrypted event was sent to another room than the one its payload was
+ * encrypted for, e.g. because the server moved it.
+ */
+public class RoomIdMismatchException extends MegolmDecryptionException {
+    private final String expectedRoomId;
+    private final String actualRoomId;
+
+    public RoomIdMismatchException(String message, String expectedRoomId, String actualRoomId) {
+        super(message);
+        this.expectedRoomId = expectedRoomId;
+        this.actualRoomId = actualRoomId;
+    }
+
+    /** The room the event was received in. */
+    public String getExpectedRoomId() {
+        return expectedRoomId;
+    }
+
+    /** The room of the decrypted payload, {@code null} if it has none. */
+    public String getActualRoomId() {
+        return actualRoomId;
+    }
+}
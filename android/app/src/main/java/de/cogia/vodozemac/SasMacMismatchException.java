@@ -0,0 +1,8 @@
+package de.cogia.vodozemac;
+
+/** The MAC received from the other side of a SAS verification didn't match. */
+public class SasMacMismatchException extends OlmException {
+    public SasMacMismatchException(String message) {
+        super(message);
+    }
+}
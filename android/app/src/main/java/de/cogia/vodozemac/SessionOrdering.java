@@ -0,0 +1,26 @@
+package de.cogia.vodozemac;
+
+/** How an {@link OlmInboundGroupSession} relates to another one with the same session id. */
+public enum SessionOrdering {
+    EQUAL(0),
+    /** The session can decrypt older messages than the other one. */
+    BETTER(1),
+    WORSE(2),
+    /** The sessions don't share a ratchet, one of them is a forgery. */
+    UNCONNECTED(3);
+
+    private final int code;
+
+    SessionOrdering(final int code) {
+        this.code = code;
+    }
+
+    static SessionOrdering fromCode(final int code) {
+        for (SessionOrdering ordering : values()) {
+            if (ordering.code == code) {
+                return ordering;
+            }
+        }
+        throw new IllegalArgumentException("Unknown session ordering " + code);
+    }
+}
@@ -0,0 +1,77 @@
+package de.cogia.vodozemac.internal;
+
+import java.lang.ref.PhantomReference;
+import java.lang.ref.ReferenceQueue;
+import java.util.Collections;
+import java.util.HashSet;
+import java.util.Set;
+import java.util.concurrent.atomic.AtomicBoolean;
+
+/**
+ * Frees native handles once their Java owner becomes unreachable.
+ *
+ * Mirrors the {@code java.lang.ref.Cleaner} API, which is only available from
+ * Android API 33, on top of a {@link PhantomReference} queue. Calling
+ * {@link Cleanable#clean()} explicitly (from {@code close()}) frees the handle
+ * right away and makes the later GC-driven run a no-op.
+ */
+public final class NativeCleaner {
+
+    public interface Free {
+        void free(long ptr);
+    }
+
+    public interface Cleanable {
+        void clean();
+    }
+
+    private static final ReferenceQueue<Object> QUEUE = new ReferenceQueue<>();
+    private static final Set<Ref> PENDING = Collections.synchronizedSet(new HashSet<Ref>());
+
+    static {
+        final Thread thread = new Thread(NativeCleaner::drain, "vodozemac-cleaner");
+        thread.setDaemon(true);
+        thread.start();
+    }
+
+    private NativeCleaner() {
+    }
+
+    public static Cleanable register(final Object owner, final long ptr, final Free free) {
+        final Ref ref = new Ref(owner, ptr, free);
+        PENDING.add(ref);
+        return ref;
+    }
+
+    private static void drain() {
+        while (true) {
+            try {
+                ((Ref) QUEUE.remove()).clean();
+            } catch (InterruptedException ignored) {
+                // keep draining, the thread lives as long as the process
+            }
+        }
+    }
+
+    private static final class Ref extends PhantomReference<Object> implements Cleanable {
+        private final long ptr;
+        private final Free free;
+        private final AtomicBoolean cleaned = new AtomicBoolean(false);
+
+        Ref(final Object owner, final long ptr, final Free free) {
+            super(owner, QUEUE);
+            this.ptr = ptr;
+            this.free = free;
+        }
+
+        @Override
+        public void clean() {
+            if (cleaned.compareAndSet(false, true)) {
+                PENDING.remove(this);
+                if (ptr != 0) {
+                    free.free(ptr);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+#!/bin/bash
+# Compares the JNI headers javac generates for the Java natives with the
+# exports in src/, a mismatch only shows up at runtime as an
+# UnsatisfiedLinkError or a garbage argument.
+
+set -euo pipefail
+
+SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
+OUT_DIR=$(mktemp -d)
+trap 'rm -rf "$OUT_DIR"' EXIT
+
+javac -nowarn -d "$OUT_DIR/classes" -h "$OUT_DIR/headers" \
+    $(find "$SCRIPT_DIR/android/app/src/main/java/de/cogia/vodozemac" -name '*.java')
+
+python3 - "$OUT_DIR/headers" "$SCRIPT_DIR/src" <<'EOF'
+import pathlib
+import re
+import sys
+
+headers, sources = map(pathlib.Path, sys.argv[1:])
+
+# The Rust types the bindings use for each JNI type.
+RUST_TYPES = {
+    "jboolean": {"jboolean", "bool"},
+    "jint": {"jint"},
+    "jlong": {"jlong"},
+    "jstring": {"JString"},
+    "jbyteArray": {"JByteArray"},
+    "jobjectArray": {"JObjectArray"},
+    "jobject": {"JObject", "JByteBuffer"},
+}
+
+# Exports without a Java native that are known and harmless.
+UNUSED_EXPORTS = {
+    # There is no `de.cogia.vodozemac.internal.Native` class (yet).
+    "Java_de_cogia_vodozemac_internal_Native_keepAlive",
+}
+
+natives = {}
+for header in headers.glob("*.h"):
+    for name, params in re.findall(r"JNICALL (Java_\w+)\s*\(([^)]*)\)", header.read_text()):
+        # Every native is static, the first two are `JNIEnv *` and `jclass`.
+        natives[name] = [param.strip() for param in params.split(",")][2:]
+
+exports = {}
+for source in sources.glob("*.rs"):
+    for name, params in re.findall(r"fn (Java_\w+)(?:<'\w+>)?\(([^)]*)\)", source.read_text()):
+        params = [param.split(":")[1].strip() for param in params.split(",") if param.strip()]
+        exports[name] = [re.sub(r"<.*>", "", param) for param in params][2:]
+
+errors = []
+for name in sorted(natives.keys() - exports.keys()):
+    errors.append(f"{name}: declared in Java but not exported")
+for name in sorted(exports.keys() - natives.keys() - UNUSED_EXPORTS):
+    errors.append(f"{name}: exported but not declared in Java")
+for name in sorted(natives.keys() & exports.keys()):
+    java, rust = natives[name], exports[name]
+    if len(java) != len(rust) or any(r not in RUST_TYPES.get(j, {j}) for j, r in zip(java, rust)):
+        errors.append(f"{name}: Java passes ({', '.join(java)}), Rust takes ({', '.join(rust)})")
+
+print("\n".join(errors) or f"{len(natives)} natives match their exports")
+sys.exit(1 if errors else 0)
+EOF
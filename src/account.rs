@@ -1,13 +1,48 @@
-use std::collections::HashMap;
-use vodozemac::base64_decode;
-use vodozemac::olm::{InboundCreationResult, SessionConfig};
-use std::error::Error;
+use std::collections::{HashMap, HashSet};
+use std::ops::DerefMut;
+use vodozemac::olm::InboundCreationResult;
 use jni::JNIEnv;
-use jni::objects::{JClass, JObject, JString, JValue};
-use jni::sys::{jlong, jstring};
-use super::{session::Session, OlmMessage, IdentityKeys, CustomError, jstring_to_string, result_or_java_exception};
+use jni::objects::{JByteArray, JClass, JObject, JObjectArray, JString, JValue};
+use jni::sys::{jboolean, jint, jlong, jobjectArray, jstring};
+use serde_json::{json, Value};
+use vodozemac::KeyId;
+use super::{canonical_json::{self, JsonObject}, curve25519::Curve25519PublicKey, errors::OlmError, handles, jbytes_to_secret, jni_call, session::Session, one_time_keys::{one_time_keys_to_java, OneTimeKey, OneTimeKeyGenerationResult}, pickle_key::PickleKey, session_config::OlmSessionConfig, olm_message::{OlmMessage, OlmMessageType}, IdentityKeys, jstring_to_string};
 
 
+/// The encryption algorithms advertised in the device keys.
+const SUPPORTED_ALGORITHMS: [&str; 2] = ["m.olm.v1.curve25519-aes-sha2", "m.megolm.v1.aes-sha2"];
+
+fn json_object(value: Value) -> JsonObject {
+    match value {
+        Value::Object(object) => object,
+        _ => unreachable!("only called with object literals"),
+    }
+}
+
+/// An encrypted account as uploaded to `/dehydrated_device`.
+pub struct DehydratedDevice {
+    pub ciphertext: String,
+    pub nonce: String,
+}
+
+fn dehydration_key(key: &[u8]) -> Result<&[u8; 32], OlmError> {
+    key.try_into()
+        .map_err(|_| OlmError::InvalidArgument("The dehydration key must be 32 bytes long".to_owned()))
+}
+
+/// The session `decrypt_or_create_session` decrypted a message with.
+pub enum DecryptingSession {
+    /// The index of one of the candidate sessions.
+    Existing(usize),
+    /// A session the pre-key message established.
+    Created(Box<Session>),
+}
+
+pub struct OlmDecryption {
+    pub session: DecryptingSession,
+    pub plaintext: Vec<u8>,
+}
+
 pub struct Account {
     inner: vodozemac::olm::Account,
 }
@@ -20,7 +55,7 @@ impl Account {
         }
     }
 
-    pub fn identity_keys(&self) -> Result<IdentityKeys, Box<dyn Error>> {
+    pub fn identity_keys(&self) -> Result<IdentityKeys, OlmError> {
         let identity_keys = self.inner.identity_keys();//.map_err(|_| {});
         Ok(
             IdentityKeys {
@@ -30,36 +65,48 @@ impl Account {
         )
     }
 
-    pub fn from_pickle(pickle: String, pickle_key: String) -> Result<Account, Box<dyn Error>> {
-        let pickle_key: &[u8; 32] = pickle_key
-            .as_bytes()
-            .try_into()
-            .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
-
-        let pickle = vodozemac::olm::AccountPickle::from_encrypted(&pickle, pickle_key)
-            .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
-
+    pub fn from_pickle(pickle: String, pickle_key: &PickleKey) -> Result<Account, OlmError> {
+        let pickle = vodozemac::olm::AccountPickle::from_encrypted(&pickle, pickle_key.as_bytes())?;
 
         let inner = vodozemac::olm::Account::from_pickle(pickle);
 
         Ok(Self { inner })
     }
 
-    pub fn from_libolm_pickle(pickle: String, pickle_key: String) -> Result<Account, Box<dyn Error>> {
+    pub fn from_libolm_pickle(pickle: String, pickle_key: &[u8]) -> Result<Account, OlmError> {
         let inner =
-            vodozemac::olm::Account::from_libolm_pickle(&pickle, &pickle_key.as_bytes())
-                .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+            vodozemac::olm::Account::from_libolm_pickle(&pickle, pickle_key)?;
 
         Ok(Self { inner })
     }
 
-    pub fn pickle(&self, pickle_key: String) -> Result<String, Box<dyn Error>> {
-        let pickle_key: &[u8; 32] = pickle_key
-            .as_bytes()
-            .try_into()
-            .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+    pub fn pickle(&self, pickle_key: &PickleKey) -> String {
+        self.inner.pickle().encrypt(pickle_key.as_bytes())
+    }
+
+    /// Pickles the account in the libolm format, for components still built
+    /// on libolm. Any `pickle_key` length libolm accepts works here too.
+    pub fn to_libolm_pickle(&self, pickle_key: &[u8]) -> Result<String, OlmError> {
+        Ok(self.inner.to_libolm_pickle(pickle_key)?)
+    }
 
-        Ok(self.inner.pickle().encrypt(pickle_key))
+    /// Encrypts the account as an [MSC3814] dehydrated device, returns the
+    /// base64 ciphertext and nonce to upload.
+    ///
+    /// Only the keys are stored, so this is meant for a freshly created
+    /// account without any sessions.
+    ///
+    /// [MSC3814]: https://github.com/matrix-org/matrix-spec-proposals/pull/3814
+    pub fn to_dehydrated_device(&self, key: &[u8]) -> Result<DehydratedDevice, OlmError> {
+        let device = self.inner.to_dehydrated_device(dehydration_key(key)?)?;
+
+        Ok(DehydratedDevice { ciphertext: device.ciphertext, nonce: device.nonce })
+    }
+
+    pub fn from_dehydrated_device(ciphertext: &str, nonce: &str, key: &[u8]) -> Result<Account, OlmError> {
+        let inner = vodozemac::olm::Account::from_dehydrated_device(ciphertext, nonce, dehydration_key(key)?)?;
+
+        Ok(Self { inner })
     }
 
     pub fn ed25519_key(&self) -> String {
@@ -74,40 +121,173 @@ impl Account {
         self.inner.sign(&message).to_base64()
     }
 
-    pub fn max_number_of_one_time_keys(&self) -> u32 {
-        self.inner.max_number_of_one_time_keys().try_into().unwrap()
+    /// Signs the canonical form of the JSON object `json` and returns it with
+    /// the signature added under `signatures.<user_id>.ed25519:<device_id>`.
+    /// Signatures already present are kept.
+    pub fn sign_json(&self, json: &str, user_id: &str, device_id: &str) -> Result<String, OlmError> {
+        let mut object = canonical_json::parse_object(json)?;
+        self.sign_object(&mut object, user_id, device_id)?;
+
+        Ok(serde_json::to_string(&object)?)
+    }
+
+    fn sign_object(&self, object: &mut JsonObject, user_id: &str, device_id: &str) -> Result<(), OlmError> {
+        let signature = self.inner.sign(&canonical_json::to_canonical_json(object)?);
+
+        let signatures = object
+            .entry("signatures")
+            .or_insert_with(|| Value::Object(Default::default()))
+            .as_object_mut()
+            .ok_or_else(|| OlmError::InvalidArgument("`signatures` must be an object".to_owned()))?
+            .entry(user_id)
+            .or_insert_with(|| Value::Object(Default::default()))
+            .as_object_mut()
+            .ok_or_else(|| OlmError::InvalidArgument(format!("`signatures.{user_id}` must be an object")))?;
+        signatures.insert(canonical_json::ed25519_key_id(device_id), signature.to_base64().into());
+
+        Ok(())
+    }
+
+    /// The signed `device_keys` object of this device for `/keys/upload`.
+    pub fn device_keys(&self, user_id: &str, device_id: &str) -> Result<JsonObject, OlmError> {
+        let mut device_keys = json_object(json!({
+            "user_id": user_id,
+            "device_id": device_id,
+            "algorithms": SUPPORTED_ALGORITHMS,
+            "keys": {
+                format!("curve25519:{device_id}"): self.curve25519_key(),
+                format!("ed25519:{device_id}"): self.ed25519_key(),
+            },
+        }));
+        self.sign_object(&mut device_keys, user_id, device_id)?;
+
+        Ok(device_keys)
+    }
+
+    /// The unpublished one-time keys as signed `signed_curve25519` key
+    /// objects, keyed by `signed_curve25519:<key id>`.
+    pub fn signed_one_time_keys(&self, user_id: &str, device_id: &str) -> Result<JsonObject, OlmError> {
+        self.signed_curve25519_keys(self.inner.one_time_keys(), false, user_id, device_id)
+    }
+
+    /// The unpublished fallback key, if any, as a signed `signed_curve25519`
+    /// key object marked with `fallback: true`.
+    pub fn signed_fallback_keys(&self, user_id: &str, device_id: &str) -> Result<JsonObject, OlmError> {
+        self.signed_curve25519_keys(self.inner.fallback_key(), true, user_id, device_id)
     }
 
-    pub fn one_time_keys(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
-        let _keys: HashMap<_, _> = self
+    fn signed_curve25519_keys(
+        &self,
+        keys: HashMap<KeyId, vodozemac::Curve25519PublicKey>,
+        fallback: bool,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<JsonObject, OlmError> {
+        let mut signed_keys = JsonObject::new();
+
+        for (key_id, key) in keys {
+            let mut key_object = json_object(json!({ "key": key.to_base64() }));
+            if fallback {
+                key_object.insert("fallback".to_owned(), true.into());
+            }
+            self.sign_object(&mut key_object, user_id, device_id)?;
+
+            signed_keys.insert(format!("signed_curve25519:{}", key_id.to_base64()), key_object.into());
+        }
+
+        Ok(signed_keys)
+    }
+
+    /// The body of a `/keys/upload` request with the unpublished one-time and
+    /// fallback keys, and the device keys if `include_device_keys` is set.
+    /// Empty sections are left out.
+    ///
+    /// Call `mark_keys_as_published` once the server accepted the upload.
+    pub fn keys_upload_body(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        include_device_keys: bool,
+    ) -> Result<String, OlmError> {
+        let mut body = JsonObject::new();
+
+        if include_device_keys {
+            body.insert("device_keys".to_owned(), self.device_keys(user_id, device_id)?.into());
+        }
+
+        let one_time_keys = self.signed_one_time_keys(user_id, device_id)?;
+        if !one_time_keys.is_empty() {
+            body.insert("one_time_keys".to_owned(), one_time_keys.into());
+        }
+
+        let fallback_keys = self.signed_fallback_keys(user_id, device_id)?;
+        if !fallback_keys.is_empty() {
+            body.insert("fallback_keys".to_owned(), fallback_keys.into());
+        }
+
+        Ok(serde_json::to_string(&body)?)
+    }
+
+    pub fn max_number_of_one_time_keys(&self) -> usize {
+        self.inner.max_number_of_one_time_keys()
+    }
+
+    /// The one-time keys that haven't been published yet, ordered by key id.
+    pub fn one_time_keys(&self) -> Vec<OneTimeKey> {
+        let mut keys: Vec<_> = self
             .inner
             .one_time_keys()
             .into_iter()
-            .map(|(k, v)| (k.to_base64(), v.to_base64()))
+            .map(|(key_id, key)| OneTimeKey { key_id, key: key.into() })
             .collect();
+        keys.sort_by_key(|key| key.key_id);
 
-        Ok(_keys)
+        keys
     }
 
-    pub fn generate_one_time_keys(&mut self, count: u32) {
-        self.inner.generate_one_time_keys(count.try_into().unwrap());
-    }
+    pub fn generate_one_time_keys(&mut self, count: usize) -> OneTimeKeyGenerationResult {
+        let result = self.inner.generate_one_time_keys(count);
+        let created_keys: HashSet<_> = result.created.into_iter().collect();
 
+        // New keys are always unpublished, so their ids can be looked up there.
+        let created = self
+            .one_time_keys()
+            .into_iter()
+            .filter(|key| created_keys.contains(&key.key.inner))
+            .collect();
 
-    pub fn fallback_key(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
-        let _keys: HashMap<String, String> = self
-            .inner
+        OneTimeKeyGenerationResult {
+            created,
+            removed: result.removed.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// The fallback key, unless it was already published.
+    pub fn fallback_key(&self) -> Option<OneTimeKey> {
+        self.inner
             .fallback_key()
             .into_iter()
-            .map(|(k, v)| (k.to_base64(), v.to_base64()))
-            .collect();
+            .next()
+            .map(|(key_id, key)| OneTimeKey { key_id, key: key.into() })
+    }
+
+    /// Creates a new fallback key. The previous one is kept so pre-key messages
+    /// that were sent to it before the new one got published can still be
+    /// decrypted, the one before that is discarded and returned.
+    pub fn generate_fallback_key(&mut self) -> Option<Curve25519PublicKey> {
+        self.inner.generate_fallback_key().map(Into::into)
+    }
 
-        Ok(_keys)
+    /// Discards the previous fallback key, returns `false` if there was none.
+    ///
+    /// Call this once a pre-key message for the current fallback key arrived,
+    /// from then on nobody should be using the previous one anymore.
+    pub fn forget_fallback_key(&mut self) -> bool {
+        self.inner.forget_fallback_key()
     }
 
-    pub fn generate_fallback_key(&mut self) {
-        self.inner.generate_fallback_key()
-        ;
+    pub fn has_unpublished_fallback_key(&self) -> bool {
+        !self.inner.fallback_key().is_empty()
     }
 
     pub fn mark_keys_as_published(&mut self) {
@@ -118,17 +298,17 @@ impl Account {
         &self,
         identity_key: String,
         one_time_key: String,
-        config: &mut SessionConfig
-    ) -> Result<Session, Box<dyn Error>> {
-        let _config = if config.version() == 2 { vodozemac::megolm::SessionConfig::version_2() } else { vodozemac::megolm::SessionConfig::version_1() };
-
+        config: &OlmSessionConfig
+    ) -> Result<Session, OlmError> {
         let identity_key =
-            vodozemac::Curve25519PublicKey::from_base64(&identity_key).map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+            vodozemac::Curve25519PublicKey::from_base64(&identity_key)
+                .map_err(OlmError::key("curve25519"))?;
         let one_time_key =
-            vodozemac::Curve25519PublicKey::from_base64(&one_time_key).map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+            vodozemac::Curve25519PublicKey::from_base64(&one_time_key)
+                .map_err(OlmError::key("curve25519"))?;
         let session = self
             .inner
-            .create_outbound_session(SessionConfig::version_2(), identity_key, one_time_key);
+            .create_outbound_session(config.inner, identity_key, one_time_key);
 
         Ok(Session { inner: session })
     }
@@ -137,93 +317,120 @@ impl Account {
         &mut self,
         identity_key: String,
         message: &OlmMessage,
-    ) -> Result<InboundCreationResult, Box<dyn Error>> {
+    ) -> Result<InboundCreationResult, OlmError> {
         let identity_key =
             vodozemac::Curve25519PublicKey::from_base64(&identity_key)
-                .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
-
-        let _message = vodozemac::olm::OlmMessage::from_parts(
-            message.message_type.try_into().unwrap(),
-            &base64_decode(&message.ciphertext).map_err(|err| Box::new(err) as Box<dyn Error>)?
-           // &(base64_decode(&message.ciphertext).unwrap())
-            // &message.ciphertext.as_bytes()
-        )
-            .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+                .map_err(OlmError::key("curve25519"))?;
 
-        if let vodozemac::olm::OlmMessage::PreKey(m) = _message {
+        if let vodozemac::olm::OlmMessage::PreKey(m) = &message.inner {
             let res = self
                 .inner
-                .create_inbound_session(identity_key, &m)
-                .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+                .create_inbound_session(identity_key, m)?;
 
             Ok(res)
         } else {
-            Err(Box::new(CustomError("Invalid message type, expected a pre-key message".to_owned())))
+            Err(OlmError::InvalidArgument("Invalid message type, expected a pre-key message".to_owned()))
+        }
+    }
+
+    /// Decrypts a message from `sender_key` with the first of `sessions` that
+    /// can, or else establishes a new session if it is a pre-key message.
+    ///
+    /// A pre-key message is only tried with the session it belongs to, if
+    /// that one fails the message is broken and no new session is created.
+    /// The sessions are taken one at a time, so they can be lock guards.
+    pub fn decrypt_or_create_session<S: DerefMut<Target = Session>>(
+        &mut self,
+        sender_key: &str,
+        message: &OlmMessage,
+        sessions: impl IntoIterator<Item = S>,
+    ) -> Result<OlmDecryption, OlmError> {
+        for (index, mut session) in sessions.into_iter().enumerate() {
+            let plaintext = match message.message_type() {
+                OlmMessageType::PreKey if session.session_matches(message) => Some(session.decrypt_bytes(message)?),
+                OlmMessageType::PreKey => None,
+                OlmMessageType::Normal => session.decrypt_bytes(message).ok(),
+            };
+
+            if let Some(plaintext) = plaintext {
+                return Ok(OlmDecryption { session: DecryptingSession::Existing(index), plaintext });
+            }
+        }
+
+        match message.message_type() {
+            OlmMessageType::PreKey => {
+                let result = self.create_inbound_session(sender_key.to_owned(), message)?;
+
+                Ok(OlmDecryption {
+                    session: DecryptingSession::Created(Box::new(Session { inner: result.session })),
+                    plaintext: result.plaintext,
+                })
+            }
+            OlmMessageType::Normal => Err(OlmError::NoMatchingSession { sender_key: sender_key.to_owned() }),
         }
     }
 }
 
 #[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1new() -> jlong {
-    Box::into_raw(Box::new(Account::new())) as jlong
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1new(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    jni_call(&mut env, |_| Ok(handles::insert(Account::new())))
 }
 
 #[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1identity_1keys(mut env: JNIEnv, my_ptr: jlong) -> JObject {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
-    let keys;
-
-    match result_or_java_exception(&mut env, acc.identity_keys()) {
-        Ok(value) => {
-
-            let java_class = env.find_class("de/cogia/vodozemac/IdentityKeys").unwrap();
-
-            let ed25519 = env.new_string(value.ed25519).unwrap();
-            let curve25519 = env.new_string(value.curve25519).unwrap();
-            let args: &[JValue] = &[
-                (&ed25519).into(),
-                (&curve25519).into(),
-            ];
-
-            keys = env.new_object(
-                java_class,
-                "(Ljava/lang/String;Ljava/lang/String;)V",
-                args
-            )
-                .unwrap();
-        }
-        Err(_) => {
-            keys = JObject::null();
-        }
-    }
-
-    keys
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        // Once the last reference is gone, vodozemac's `ZeroizeOnDrop` impls wipe the key material.
+        handles::free_handle::<Account>(my_ptr);
+        Ok(())
+    })
 }
 
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1identity_1keys<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let value = acc.lock().identity_keys()?;
+
+        let ed25519 = env.new_string(value.ed25519)?;
+        let curve25519 = env.new_string(value.curve25519)?;
+        let args: &[JValue] = &[
+            (&ed25519).into(),
+            (&curve25519).into(),
+        ];
+
+        Ok(env.new_object(
+            "de/cogia/vodozemac/IdentityKeys",
+            "(Ljava/lang/String;Ljava/lang/String;)V",
+            args
+        )?)
+    })
+}
 
 #[no_mangle]
 pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1pickle(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
-    pickle_key: JString
+    pickle_key: jlong
 ) -> jstring {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
-    let p_key: String = env.get_string(&pickle_key).expect("Couldn't get Java string").into();
-
-    let pickle;
-    match result_or_java_exception(&mut env, acc.pickle(p_key)) {
-        Ok(value) => {
-            pickle = **env
-                .new_string(value)
-                .expect("Failed to create output session_id");;
-        }
-        Err(_) => {
-            pickle = std::ptr::null_mut()
-        }
-    }
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let pickle_key = handles::get::<PickleKey>(pickle_key)?;
+        let pickle = acc.lock().pickle(&pickle_key.lock());
 
-    pickle
+        Ok(env.new_string(pickle)?.into_raw())
+    })
 }
 
 #[no_mangle]
@@ -231,15 +438,31 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1from_1pickle(
     mut env: JNIEnv,
     _class: JClass,
     pickle: JString,
-    pickle_key: JString
+    pickle_key: jlong
 ) -> jlong {
+    jni_call(&mut env, |env| {
+        let acc = Account::from_pickle(
+            jstring_to_string(env, pickle)?,
+            &handles::get::<PickleKey>(pickle_key)?.lock()
+        )?;
+
+        Ok(handles::insert(acc))
+    })
+}
 
-    let acc = Account::from_pickle(
-        jstring_to_string(&mut env, pickle),
-        jstring_to_string(&mut env, pickle_key)
-    );
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1pickle_1lib_1olm(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    pickle_key: JByteArray
+) -> jstring {
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let pickle = acc.lock().to_libolm_pickle(&jbytes_to_secret(env, pickle_key)?)?;
 
-    Box::into_raw(Box::new(acc)) as jlong
+        Ok(env.new_string(pickle)?.into_raw())
+    })
 }
 
 #[no_mangle]
@@ -247,15 +470,55 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1from_1pickle_1lib_1olm(
     mut env: JNIEnv,
     _class: JClass,
     pickle: JString,
-    pickle_key: JString
+    pickle_key: JByteArray
 ) -> jlong {
+    jni_call(&mut env, |env| {
+        let acc = Account::from_libolm_pickle(
+            jstring_to_string(env, pickle)?,
+            &jbytes_to_secret(env, pickle_key)?
+        )?;
+
+        Ok(handles::insert(acc))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1to_1dehydrated_1device<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    key: JByteArray,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let device = acc.lock().to_dehydrated_device(&jbytes_to_secret(env, key)?)?;
+
+        let ciphertext = env.new_string(device.ciphertext)?;
+        let nonce = env.new_string(device.nonce)?;
+
+        Ok(env.new_object(
+            "de/cogia/vodozemac/DehydratedDevice",
+            "(Ljava/lang/String;Ljava/lang/String;)V",
+            &[(&ciphertext).into(), (&nonce).into()],
+        )?)
+    })
+}
 
-    let acc = Account::from_libolm_pickle(
-        jstring_to_string(&mut env, pickle),
-        jstring_to_string(&mut env, pickle_key)
-    );
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1from_1dehydrated_1device(
+    mut env: JNIEnv,
+    _class: JClass,
+    ciphertext: JString,
+    nonce: JString,
+    key: JByteArray,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let ciphertext = jstring_to_string(env, ciphertext)?;
+        let nonce = jstring_to_string(env, nonce)?;
+        let acc = Account::from_dehydrated_device(&ciphertext, &nonce, &jbytes_to_secret(env, key)?)?;
 
-    Box::into_raw(Box::new(acc)) as jlong
+        Ok(handles::insert(acc))
+    })
 }
 
 #[no_mangle]
@@ -264,14 +527,12 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1ed25519_1key(
     _class: JClass,
     my_ptr: jlong,
 ) -> jstring {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
-
-    // Convert the output Rust String to a new jstring and return it
-    let output_jstring: jstring = **env
-        .new_string(acc.ed25519_key())
-        .expect("Failed to create output ed25519_key");
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let key = acc.lock().ed25519_key();
 
-    output_jstring
+        Ok(env.new_string(key)?.into_raw())
+    })
 }
 
 #[no_mangle]
@@ -280,14 +541,12 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1curve25519Key(
     _class: JClass,
     my_ptr: jlong,
 ) -> jstring {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let key = acc.lock().curve25519_key();
 
-    // Convert the output Rust String to a new jstring and return it
-    let output_jstring: jstring = **env
-        .new_string(acc.curve25519_key())
-        .expect("Failed to create output curve25519_key");
-
-    output_jstring
+        Ok(env.new_string(key)?.into_raw())
+    })
 }
 
 #[no_mangle]
@@ -297,97 +556,157 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1sign(
     my_ptr: jlong,
     message: JString,
 ) -> jstring {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
-
-    let msg = jstring_to_string(&mut env, message);
-    // Convert the output Rust String to a new jstring and return it
-    let output_jstring: jstring = **env
-        .new_string(acc.sign(msg))
-        .expect("Failed to create output curve25519_key");
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let msg = jstring_to_string(env, message)?;
+        let signature = acc.lock().sign(msg);
 
-    output_jstring
+        Ok(env.new_string(signature)?.into_raw())
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1maxNumberOfOneTimeKeys(
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1sign_1json(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
-) -> jlong {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
-    acc.max_number_of_one_time_keys() as jlong
+    json: JString,
+    user_id: JString,
+    device_id: JString,
+) -> jstring {
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let json = jstring_to_string(env, json)?;
+        let user_id = jstring_to_string(env, user_id)?;
+        let device_id = jstring_to_string(env, device_id)?;
+        let signed = acc.lock().sign_json(&json, &user_id, &device_id)?;
+
+        Ok(env.new_string(signed)?.into_raw())
+    })
 }
 
-
 #[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1oneTimeKeys(
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1keys_1upload_1body(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
+    user_id: JString,
+    device_id: JString,
+    include_device_keys: jboolean,
 ) -> jstring {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
-
-    let keys;
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let user_id = jstring_to_string(env, user_id)?;
+        let device_id = jstring_to_string(env, device_id)?;
+        let body = acc.lock().keys_upload_body(&user_id, &device_id, include_device_keys)?;
+
+        Ok(env.new_string(body)?.into_raw())
+    })
+}
 
-    match result_or_java_exception(&mut env, acc.one_time_keys()){
-        Ok(value) => {
-            keys = **env
-                .new_string(serde_json::to_string(&value).unwrap())
-                .expect("Failed to create output ed25519_key");
-        }
-        Err(_) => {
-            keys = std::ptr::null_mut()
-        }
-    }
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1maxNumberOfOneTimeKeys(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let max = acc.lock().max_number_of_one_time_keys();
 
-    keys
+        Ok(max as jlong)
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1generateOneTimeKeys(
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1one_1time_1keys(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
-    amount: jlong,
-) {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
-    acc.generate_one_time_keys(amount as u32);
-}
+) -> jobjectArray {
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let keys = acc.lock().one_time_keys();
 
-// fallback_key
+        Ok(one_time_keys_to_java(env, keys)?.into_raw())
+    })
+}
 
 #[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1fallbackKey(
-    mut env: JNIEnv,
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1generate_1one_1time_1keys<'a>(
+    mut env: JNIEnv<'a>,
     _class: JClass,
     my_ptr: jlong,
-) -> jstring {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
+    amount: jlong,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let result = acc.lock().generate_one_time_keys(usize::try_from(amount)?);
+
+        result.into_java(env)
+    })
+}
 
-    let keys;
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1fallback_1key<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let key = acc.lock().fallback_key();
 
-    match result_or_java_exception(&mut env, acc.fallback_key()) {
-        Ok(value) => {
-            keys = **env
-                .new_string(serde_json::to_string(&value).unwrap())
-                .expect("Failed to create output fallback_key");
+        match key {
+            Some(key) => key.to_java(env),
+            None => Ok(JObject::null()),
         }
-        Err(_) => {
-            keys = std::ptr::null_mut()
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1generate_1fallback_1key<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let removed = acc.lock().generate_fallback_key();
+
+        match removed {
+            Some(key) => key.to_java(env),
+            None => Ok(JObject::null()),
         }
-    }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1forget_1fallback_1key(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jboolean {
+    jni_call(&mut env, |_| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let forgotten = acc.lock().forget_fallback_key();
 
-    keys
+        Ok(forgotten)
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1generateFallbackKey(
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1has_1unpublished_1fallback_1key(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
-) {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
-    acc.generate_fallback_key();
+) -> jboolean {
+    jni_call(&mut env, |_| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let has_key = acc.lock().has_unpublished_fallback_key();
+
+        Ok(has_key)
+    })
 }
 
 #[no_mangle]
@@ -396,8 +715,12 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1markKeysAsPublished(
     _class: JClass,
     my_ptr: jlong,
 ) {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
-    acc.mark_keys_as_published();
+    jni_call(&mut env, |_| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        acc.lock().mark_keys_as_published();
+
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -409,25 +732,52 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1createOutboundSession(
     one_time_key: JString,
     config: jlong
 ) -> jlong {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
-    let session_config = unsafe { &mut *(config as *mut SessionConfig) };
-    let ik = jstring_to_string(&mut env, identity_key);
-    let otk = jstring_to_string(&mut env, one_time_key);
-
-    let session;
-    match result_or_java_exception(&mut env, acc.create_outbound_session(ik, otk, session_config)) {
-        Ok(value) => {
-            session = Box::into_raw(Box::new(value)) as jlong;
-        }
-        Err(_) => {
-            session = 0
-        }
-    }
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let session_config = handles::get::<OlmSessionConfig>(config)?;
+        let ik = jstring_to_string(env, identity_key)?;
+        let otk = jstring_to_string(env, one_time_key)?;
+
+        let session = acc.lock().create_outbound_session(ik, otk, &session_config.lock())?;
+
+        Ok(handles::insert(session))
+    })
+}
 
-    session
+fn new_inbound_session(
+    env: &mut JNIEnv,
+    my_ptr: jlong,
+    identity_key: JString,
+    message_ptr: jlong,
+) -> Result<InboundCreationResult, OlmError> {
+    let acc = handles::get::<Account>(my_ptr)?;
+
+    let ik = jstring_to_string(env, identity_key)?;
+    let message = handles::get::<OlmMessage>(message_ptr)?;
+
+    let value = acc.lock().create_inbound_session(ik, &message.lock())?;
 
+    Ok(value)
 }
 
+/// Wraps the new session in `class`, whose constructor takes the plaintext
+/// and the session handle.
+fn inbound_creation_result<'a>(
+    env: &mut JNIEnv<'a>,
+    class: &str,
+    signature: &str,
+    plaintext: &JObject,
+    session: vodozemac::olm::Session,
+) -> Result<JObject<'a>, OlmError> {
+    let ptr = handles::insert(Session { inner: session });
+
+    let result = env.new_object(class, signature, &[plaintext.into(), ptr.into()]);
+    if result.is_err() {
+        let _ = handles::remove::<Session>(ptr);
+    }
+
+    Ok(result?)
+}
 
 #[no_mangle]
 pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1createInboundSession<'a>(
@@ -435,46 +785,406 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1createInboundSession<'a>(
     _class: JClass,
     my_ptr: jlong,
     identity_key: JString<'a>,
-    chipertext: JString<'a>,
-    message_type: jlong
+    message_ptr: jlong,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let value = new_inbound_session(env, my_ptr, identity_key, message_ptr)?;
+
+        // The one-time key is used up either way, a caller that can't rule
+        // out binary plaintext should use `_createInboundSessionBytes`.
+        let message = String::from_utf8(value.plaintext).map_err(OlmError::from)?;
+        let jmessage = env.new_string(&message)?;
+
+        inbound_creation_result(
+            env,
+            "de/cogia/vodozemac/InboundCreationResult",
+            "(Ljava/lang/String;J)V",
+            &jmessage,
+            value.session,
+        )
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1createInboundSessionBytes<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    identity_key: JString<'a>,
+    message_ptr: jlong,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let value = new_inbound_session(env, my_ptr, identity_key, message_ptr)?;
+
+        let plaintext = env.byte_array_from_slice(&value.plaintext)?;
+
+        inbound_creation_result(
+            env,
+            "de/cogia/vodozemac/InboundCreationBytes",
+            "([BJ)V",
+            &plaintext,
+            value.session,
+        )
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmAccount__1decrypt_1or_1create_1session<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    sender_key: JString<'a>,
+    message_ptr: jlong,
+    sessions: JObjectArray<'a>,
 ) -> JObject<'a> {
-    let acc = unsafe { &mut *(my_ptr as *mut Account) };
-
-    let ik = jstring_to_string(&mut env, identity_key);
-    let message = OlmMessage {
-        ciphertext: jstring_to_string(&mut env, chipertext),
-        message_type: message_type as u32
-    };
-
-    let res;
-    match result_or_java_exception(&mut env, acc.create_inbound_session(ik, &message)) {
-        Ok(value) => {
-            let session = Session { inner: value.session };
-            let ptr = Box::into_raw(Box::new(session)) as jlong;
-            let message = String::from_utf8_lossy(&value.plaintext).to_string();
-            let jmessage  =  env.new_string(&message).unwrap();
-
-            let java_class = env.find_class("de/cogia/vodozemac/InboundCreationResult").unwrap();
-
-            let args: &[JValue] = &[
-                (&jmessage).into(),
-                (ptr).into(),
-            ];
-
-            let java_object = env.new_object(
-                java_class,
-                "(Ljava/lang/String;J)V",
-                args
-            ).unwrap();
-
-            res = java_object;
+    jni_call(&mut env, |env| {
+        let acc = handles::get::<Account>(my_ptr)?;
+        let message = handles::get::<OlmMessage>(message_ptr)?;
+        let sender_key = jstring_to_string(env, sender_key)?;
+
+        let length = env.get_array_length(&sessions)?;
+        let mut candidates = Vec::with_capacity(usize::try_from(length)?);
+        for index in 0..length {
+            let session = env.get_object_array_element(&sessions, index)?;
+            let ptr = env.call_method(&session, "getPtr", "()J", &[])?.j()?;
+            env.delete_local_ref(session)?;
+            candidates.push(handles::get::<Session>(ptr)?);
         }
-        Err(_) => {
-            res = JObject::null();
+
+        let decryption = acc.lock().decrypt_or_create_session(
+            &sender_key,
+            &message.lock(),
+            candidates.iter().map(handles::Handle::lock),
+        )?;
+
+        let plaintext = env.byte_array_from_slice(&decryption.plaintext)?;
+        let (session, created) = match decryption.session {
+            DecryptingSession::Existing(index) => {
+                (env.get_object_array_element(&sessions, jint::try_from(index)?)?, false)
+            }
+            DecryptingSession::Created(session) => {
+                let ptr = handles::insert(*session);
+                let session = env.new_object("de/cogia/vodozemac/OlmSession", "(J)V", &[JValue::Long(ptr)]);
+                if session.is_err() {
+                    let _ = handles::remove::<Session>(ptr);
+                }
+                (session?, true)
+            }
+        };
+
+        Ok(env.new_object(
+            "de/cogia/vodozemac/OlmDecryptionResult",
+            "(Lde/cogia/vodozemac/OlmSession;Z[B)V",
+            &[(&session).into(), JValue::from(created), (&plaintext).into()],
+        )?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ed25519::Ed25519PublicKey;
+    use crate::test_utils::{key, OTHER_KEY, PICKLE_KEY};
+
+    #[test]
+    fn pickle_round_trip() {
+        let account = Account::new();
+        let pickle = account.pickle(&key(PICKLE_KEY));
+
+        let unpickled = Account::from_pickle(pickle, &key(PICKLE_KEY)).unwrap();
+
+        assert_eq!(account.curve25519_key(), unpickled.curve25519_key());
+        assert_eq!(account.ed25519_key(), unpickled.ed25519_key());
+    }
+
+    #[test]
+    fn from_pickle_with_wrong_key_fails() {
+        let pickle = Account::new().pickle(&key(PICKLE_KEY));
+
+        assert!(Account::from_pickle(pickle, &key(OTHER_KEY)).is_err());
+    }
+
+    #[test]
+    fn from_pickle_with_truncated_pickle_fails() {
+        let pickle = Account::new().pickle(&key(PICKLE_KEY));
+        let truncated = pickle[..pickle.len() / 2].to_owned();
+
+        assert!(Account::from_pickle(truncated, &key(PICKLE_KEY)).is_err());
+        assert!(Account::from_pickle(String::new(), &key(PICKLE_KEY)).is_err());
+    }
+
+    #[test]
+    fn signed_json_can_be_verified() {
+        let account = Account::new();
+        let key = Ed25519PublicKey::from_base64(&account.ed25519_key()).unwrap();
+        let json = r#"{"user_id": "@alice:example.org", "unsigned": {"device_display_name": "Phone"}}"#;
+
+        let signed = account.sign_json(json, "@alice:example.org", "DEVICE").unwrap();
+        let signed = account.sign_json(&signed, "@alice:example.org", "OTHER").unwrap();
+
+        let object = canonical_json::parse_object(&signed).unwrap();
+        assert_eq!(object["unsigned"]["device_display_name"], "Phone");
+        assert_eq!(object["signatures"]["@alice:example.org"].as_object().unwrap().len(), 2);
+
+        assert!(key.verify_json(&signed, "@alice:example.org", "DEVICE").is_ok());
+        assert!(key.verify_json(&signed, "@alice:example.org", "OTHER").is_ok());
+        assert!(matches!(
+            key.verify_json(&signed, "@bob:example.org", "DEVICE"),
+            Err(OlmError::MissingSignature { .. })
+        ));
+
+        let mut tampered = object.clone();
+        tampered.insert("user_id".to_owned(), "@mallory:example.org".into());
+        let tampered = serde_json::to_string(&tampered).unwrap();
+        assert!(matches!(key.verify_json(&tampered, "@alice:example.org", "DEVICE"), Err(OlmError::Signature(_))));
+    }
+
+    #[test]
+    fn keys_upload_body_is_signed() {
+        let mut account = Account::new();
+        account.generate_one_time_keys(2);
+        account.generate_fallback_key();
+        let key = Ed25519PublicKey::from_base64(&account.ed25519_key()).unwrap();
+
+        let body = account.keys_upload_body("@alice:example.org", "DEVICE", true).unwrap();
+        let body = canonical_json::parse_object(&body).unwrap();
+
+        let device_keys = &body["device_keys"];
+        assert_eq!(device_keys["device_id"], "DEVICE");
+        assert_eq!(device_keys["algorithms"], json!(SUPPORTED_ALGORITHMS));
+        assert_eq!(device_keys["keys"]["curve25519:DEVICE"], account.curve25519_key());
+        assert_eq!(device_keys["keys"]["ed25519:DEVICE"], account.ed25519_key());
+        key.verify_json(&device_keys.to_string(), "@alice:example.org", "DEVICE").unwrap();
+
+        let one_time_keys = body["one_time_keys"].as_object().unwrap();
+        assert_eq!(one_time_keys.len(), 2);
+        for (key_id, one_time_key) in one_time_keys {
+            assert!(key_id.starts_with("signed_curve25519:"));
+            assert!(one_time_key.get("fallback").is_none());
+            key.verify_json(&one_time_key.to_string(), "@alice:example.org", "DEVICE").unwrap();
+        }
+
+        let fallback_keys = body["fallback_keys"].as_object().unwrap();
+        assert_eq!(fallback_keys.len(), 1);
+        for fallback_key in fallback_keys.values() {
+            assert_eq!(fallback_key["fallback"], true);
+            key.verify_json(&fallback_key.to_string(), "@alice:example.org", "DEVICE").unwrap();
         }
     }
 
-    res
+    #[test]
+    fn published_keys_are_left_out_of_the_upload() {
+        let mut account = Account::new();
+        account.generate_one_time_keys(1);
+        account.mark_keys_as_published();
 
-}
+        let body = account.keys_upload_body("@alice:example.org", "DEVICE", false).unwrap();
+
+        assert_eq!(body, "{}");
+    }
+
+    #[test]
+    fn generated_one_time_keys_are_reported() {
+        let mut account = Account::new();
+        let max = account.max_number_of_one_time_keys();
+
+        let result = account.generate_one_time_keys(2);
+        assert_eq!(result.created, account.one_time_keys());
+        assert!(result.removed.is_empty());
+
+        let first = account.one_time_keys()[0];
+        account.mark_keys_as_published();
+        assert!(account.one_time_keys().is_empty());
+
+        // vodozemac keeps up to 100 times as many private keys as it wants
+        // published, to be able to decrypt late pre-key messages.
+        let result = account.generate_one_time_keys(100 * max - 1);
+        assert_eq!(result.created.len(), 100 * max - 1);
+        assert!(result.created.iter().all(|key| key.key_id > first.key_id));
+        assert_eq!(result.removed, vec![first.key]);
+    }
+
+    #[test]
+    fn fallback_key_is_only_returned_until_published() {
+        let mut account = Account::new();
+        assert_eq!(account.fallback_key(), None);
+        assert!(!account.has_unpublished_fallback_key());
+
+        account.generate_fallback_key();
+        assert!(account.fallback_key().is_some());
+        assert!(account.has_unpublished_fallback_key());
+
+        account.mark_keys_as_published();
+        assert_eq!(account.fallback_key(), None);
+        assert!(!account.has_unpublished_fallback_key());
+    }
+
+    /// A pre-key message from a new account to `bob`'s current fallback key,
+    /// and the identity key of the sender.
+    fn pre_key_message_to_fallback_key(bob: &Account) -> (String, OlmMessage) {
+        let alice = Account::new();
+        let fallback_key = bob.fallback_key().unwrap().key.to_base64();
+        let mut session = alice
+            .create_outbound_session(bob.curve25519_key(), fallback_key, &OlmSessionConfig::version_2())
+            .unwrap();
 
+        (alice.curve25519_key(), session.encrypt("It's a secret to everybody"))
+    }
+
+    fn create_inbound_session(
+        bob: &mut Account,
+        (sender_key, message): &(String, OlmMessage),
+    ) -> Result<InboundCreationResult, OlmError> {
+        bob.create_inbound_session(sender_key.clone(), message)
+    }
+
+    /// An outbound session from `alice` to `bob`, using up one of his one-time keys.
+    fn outbound_session(alice: &Account, bob: &mut Account) -> Session {
+        bob.generate_one_time_keys(1);
+        let one_time_key = bob.one_time_keys()[0].key.to_base64();
+        bob.mark_keys_as_published();
+
+        alice
+            .create_outbound_session(bob.curve25519_key(), one_time_key, &OlmSessionConfig::version_2())
+            .unwrap()
+    }
+
+    #[test]
+    fn pre_key_messages_create_a_session_only_once() {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        let mut unrelated = outbound_session(&bob, &mut Account::new());
+        let mut outbound = outbound_session(&alice, &mut bob);
+
+        let decryption = bob
+            .decrypt_or_create_session(&alice.curve25519_key(), &outbound.encrypt("first"), [&mut unrelated])
+            .unwrap();
+        assert_eq!(decryption.plaintext, b"first");
+        let DecryptingSession::Created(mut inbound) = decryption.session else {
+            panic!("the pre-key message should have created a session");
+        };
+
+        let decryption = bob
+            .decrypt_or_create_session(
+                &alice.curve25519_key(),
+                &outbound.encrypt("second"),
+                [&mut unrelated, &mut *inbound],
+            )
+            .unwrap();
+        assert_eq!(decryption.plaintext, b"second");
+        assert!(matches!(decryption.session, DecryptingSession::Existing(1)));
+    }
+
+    #[test]
+    fn normal_messages_are_decrypted_by_a_matching_session() {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        let mut unrelated = outbound_session(&bob, &mut Account::new());
+        let mut outbound = outbound_session(&alice, &mut bob);
+        let mut inbound = Session {
+            inner: bob.create_inbound_session(alice.curve25519_key(), &outbound.encrypt("Hi")).unwrap().session,
+        };
+        outbound.decrypt(&inbound.encrypt("Hello")).unwrap();
+
+        let message = outbound.encrypt("normal");
+        assert_eq!(message.message_type(), OlmMessageType::Normal);
+        let decryption = bob
+            .decrypt_or_create_session(&alice.curve25519_key(), &message, [&mut unrelated, &mut inbound])
+            .unwrap();
+        assert_eq!(decryption.plaintext, b"normal");
+        assert!(matches!(decryption.session, DecryptingSession::Existing(1)));
+
+        assert!(matches!(
+            bob.decrypt_or_create_session(&alice.curve25519_key(), &outbound.encrypt("lost"), [&mut unrelated]),
+            Err(OlmError::NoMatchingSession { .. })
+        ));
+    }
+
+    #[test]
+    fn generating_a_fallback_key_removes_the_one_before_the_previous() {
+        let mut bob = Account::new();
+
+        assert_eq!(bob.generate_fallback_key(), None);
+        let first = bob.fallback_key().unwrap().key;
+        assert_eq!(bob.generate_fallback_key(), None);
+        assert_eq!(bob.generate_fallback_key(), Some(first));
+    }
+
+    #[test]
+    fn previous_fallback_key_works_until_forgotten() {
+        let mut bob = Account::new();
+        bob.generate_fallback_key();
+        let old_message = pre_key_message_to_fallback_key(&bob);
+        bob.mark_keys_as_published();
+
+        bob.generate_fallback_key();
+        let new_message = pre_key_message_to_fallback_key(&bob);
+        bob.mark_keys_as_published();
+
+        let result = create_inbound_session(&mut bob, &old_message).unwrap();
+        assert_eq!(result.plaintext, b"It's a secret to everybody");
+
+        // Fallback keys, unlike one-time keys, stay around after use.
+        assert!(create_inbound_session(&mut bob, &old_message).is_ok());
+        assert!(create_inbound_session(&mut bob, &new_message).is_ok());
+
+        assert!(bob.forget_fallback_key());
+        assert!(!bob.forget_fallback_key());
+
+        assert!(matches!(
+            create_inbound_session(&mut bob, &old_message),
+            Err(OlmError::SessionCreation(_))
+        ));
+        assert!(create_inbound_session(&mut bob, &new_message).is_ok());
+    }
+
+    #[test]
+    fn libolm_pickle_keeps_the_one_time_keys() {
+        let mut account = Account::new();
+        account.generate_one_time_keys(2);
+        account.generate_fallback_key();
+
+        let libolm_pickle = account.to_libolm_pickle(PICKLE_KEY).unwrap();
+        let unpickled = Account::from_libolm_pickle(libolm_pickle, PICKLE_KEY).unwrap();
+
+        assert_eq!(account.ed25519_key(), unpickled.ed25519_key());
+        assert_eq!(account.one_time_keys(), unpickled.one_time_keys());
+        assert_eq!(account.fallback_key(), unpickled.fallback_key());
+    }
+
+    #[test]
+    fn dehydrated_device_round_trip() {
+        let mut account = Account::new();
+        account.generate_fallback_key();
+        let message = pre_key_message_to_fallback_key(&account);
+
+        let device = account.to_dehydrated_device(PICKLE_KEY).unwrap();
+        let mut rehydrated =
+            Account::from_dehydrated_device(&device.ciphertext, &device.nonce, PICKLE_KEY).unwrap();
+
+        assert_eq!(account.curve25519_key(), rehydrated.curve25519_key());
+        assert_eq!(account.ed25519_key(), rehydrated.ed25519_key());
+        // Messages sent while the device was dehydrated can be decrypted.
+        assert!(create_inbound_session(&mut rehydrated, &message).is_ok());
+
+        assert!(matches!(
+            Account::from_dehydrated_device(&device.ciphertext, &device.nonce, OTHER_KEY),
+            Err(OlmError::DehydratedDevice(_))
+        ));
+        assert!(matches!(account.to_dehydrated_device(&[0u8; 31]), Err(OlmError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn libolm_and_vodozemac_pickles_are_not_interchangeable() {
+        let account = Account::new();
+        let pickle = account.pickle(&key(PICKLE_KEY));
+        let libolm_pickle = account.to_libolm_pickle(PICKLE_KEY).unwrap();
+
+        assert!(Account::from_libolm_pickle(pickle, PICKLE_KEY).is_err());
+        assert!(Account::from_pickle(libolm_pickle.clone(), &key(PICKLE_KEY)).is_err());
+        assert!(Account::from_libolm_pickle(libolm_pickle.clone(), OTHER_KEY).is_err());
+
+        let unpickled = Account::from_libolm_pickle(libolm_pickle, PICKLE_KEY).unwrap();
+        assert_eq!(account.curve25519_key(), unpickled.curve25519_key());
+    }
+}
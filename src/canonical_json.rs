@@ -0,0 +1,86 @@
+//! [Canonical JSON] as used for Matrix signatures.
+//!
+//! [Canonical JSON]: https://spec.matrix.org/v1.11/appendices/#canonical-json
+
+use serde_json::{Map, Value};
+
+use super::errors::OlmError;
+
+/// Integers outside of this range can't be represented exactly by every
+/// JSON implementation, the spec forbids them.
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+pub type JsonObject = Map<String, Value>;
+
+pub fn parse_object(json: &str) -> Result<JsonObject, OlmError> {
+    match serde_json::from_str(json)? {
+        Value::Object(object) => Ok(object),
+        _ => Err(OlmError::InvalidArgument("The JSON must be an object".to_owned())),
+    }
+}
+
+/// Encodes `object` the way it is signed: without its `signatures` and
+/// `unsigned` fields, with sorted keys and without any whitespace.
+pub fn to_canonical_json(object: &JsonObject) -> Result<String, OlmError> {
+    let mut object = object.clone();
+    object.remove("signatures");
+    object.remove("unsigned");
+
+    let object = Value::Object(object);
+    check_numbers(&object)?;
+
+    // Without the `preserve_order` feature `serde_json` keeps objects in a
+    // `BTreeMap`, so the keys are already sorted by code point.
+    Ok(serde_json::to_string(&object)?)
+}
+
+fn check_numbers(value: &Value) -> Result<(), OlmError> {
+    match value {
+        Value::Number(number) => match number.as_i64() {
+            Some(number) if (-MAX_SAFE_INTEGER..=MAX_SAFE_INTEGER).contains(&number) => Ok(()),
+            _ => Err(OlmError::InvalidArgument(format!(
+                "Canonical JSON only allows integers between -(2^53)+1 and (2^53)-1, got {number}"
+            ))),
+        },
+        Value::Array(values) => values.iter().try_for_each(check_numbers),
+        Value::Object(object) => object.values().try_for_each(check_numbers),
+        Value::Null | Value::Bool(_) | Value::String(_) => Ok(()),
+    }
+}
+
+/// The key under which `signatures.<user_id>` holds a device's signature.
+pub fn ed25519_key_id(device_id: &str) -> String {
+    format!("ed25519:{device_id}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_json_is_sorted_and_compact() {
+        let object = parse_object(
+            r#"{
+                "b": 1, "a": { "d": [1, 2], "c": "é" },
+                "signatures": { "@alice:example.org": {} },
+                "unsigned": { "age": 1 }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(to_canonical_json(&object).unwrap(), r#"{"a":{"c":"é","d":[1,2]},"b":1}"#);
+    }
+
+    #[test]
+    fn only_safe_integers_are_allowed() {
+        assert!(to_canonical_json(&parse_object(r#"{"a": 9007199254740991}"#).unwrap()).is_ok());
+        assert!(to_canonical_json(&parse_object(r#"{"a": 9007199254740992}"#).unwrap()).is_err());
+        assert!(to_canonical_json(&parse_object(r#"{"a": [1.5]}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn only_objects_can_be_signed() {
+        assert!(parse_object("[]").is_err());
+        assert!(parse_object("not json").is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use jni::JNIEnv;
+use jni::objects::{JByteArray, JClass, JObject, JString, JValue};
+use jni::sys::{jbyteArray, jlong, jstring};
+
+use super::{errors::OlmError, handles, jni_call, jstring_to_string};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Curve25519PublicKey {
+    pub(crate) inner: vodozemac::Curve25519PublicKey,
+}
+
+impl Curve25519PublicKey {
+    pub fn from_base64(key: &str) -> Result<Self, OlmError> {
+        let inner = vodozemac::Curve25519PublicKey::from_base64(key).map_err(OlmError::key("curve25519"))?;
+
+        Ok(Self { inner })
+    }
+
+    pub fn from_bytes(key: &[u8]) -> Result<Self, OlmError> {
+        let inner = vodozemac::Curve25519PublicKey::from_slice(key).map_err(OlmError::key("curve25519"))?;
+
+        Ok(Self { inner })
+    }
+
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_base64(&self) -> String {
+        self.inner.to_base64()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.inner.as_bytes()
+    }
+
+    /// Wraps the key in a `de.cogia.vodozemac.Curve25519PublicKey` which owns
+    /// a new handle to it.
+    pub fn to_java<'a>(self, env: &mut JNIEnv<'a>) -> Result<JObject<'a>, OlmError> {
+        let ptr = handles::insert(self);
+
+        let object = env.new_object(
+            "de/cogia/vodozemac/Curve25519PublicKey",
+            "(J)V",
+            &[JValue::Long(ptr)],
+        );
+        if object.is_err() {
+            let _ = handles::remove::<Curve25519PublicKey>(ptr);
+        }
+
+        Ok(object?)
+    }
+}
+
+impl From<vodozemac::Curve25519PublicKey> for Curve25519PublicKey {
+    fn from(inner: vodozemac::Curve25519PublicKey) -> Self {
+        Self { inner }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Curve25519PublicKey__1from_1base64(
+    mut env: JNIEnv,
+    _class: JClass,
+    key: JString,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let key = Curve25519PublicKey::from_base64(&jstring_to_string(env, key)?)?;
+
+        Ok(handles::insert(key))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Curve25519PublicKey__1from_1bytes(
+    mut env: JNIEnv,
+    _class: JClass,
+    key: JByteArray,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let key = Curve25519PublicKey::from_bytes(&env.convert_byte_array(key)?)?;
+
+        Ok(handles::insert(key))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Curve25519PublicKey__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        handles::free_handle::<Curve25519PublicKey>(my_ptr);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Curve25519PublicKey__1to_1base64(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jstring {
+    jni_call(&mut env, |env| {
+        let key = handles::get::<Curve25519PublicKey>(my_ptr)?;
+        let key = key.lock().to_base64();
+
+        Ok(env.new_string(key)?.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Curve25519PublicKey__1to_1bytes(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jbyteArray {
+    jni_call(&mut env, |env| {
+        let key = handles::get::<Curve25519PublicKey>(my_ptr)?;
+        let bytes = env.byte_array_from_slice(key.lock().as_bytes())?;
+
+        Ok(bytes.into_raw())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+
+    #[test]
+    fn bytes_round_trip() {
+        let account = Account::new();
+        let key = Curve25519PublicKey::from_base64(&account.curve25519_key()).unwrap();
+
+        let restored = Curve25519PublicKey::from_bytes(key.as_bytes()).unwrap();
+
+        assert_eq!(restored.to_base64(), account.curve25519_key());
+        assert!(matches!(Curve25519PublicKey::from_bytes(&[0u8; 31]), Err(OlmError::Key("curve25519", _))));
+        assert!(matches!(Curve25519PublicKey::from_base64("not a key"), Err(OlmError::Key("curve25519", _))));
+    }
+}
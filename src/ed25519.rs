@@ -0,0 +1,221 @@
+use jni::JNIEnv;
+use jni::objects::{JByteArray, JClass, JString};
+use jni::sys::{jbyteArray, jlong, jstring};
+use vodozemac::{Ed25519Signature, KeyError};
+
+use super::{canonical_json, errors::OlmError, handles, jni_call, jstring_to_string};
+
+pub struct Ed25519PublicKey {
+    pub(crate) inner: vodozemac::Ed25519PublicKey,
+}
+
+impl Ed25519PublicKey {
+    pub fn from_base64(key: &str) -> Result<Self, OlmError> {
+        let inner = vodozemac::Ed25519PublicKey::from_base64(key).map_err(OlmError::key("ed25519"))?;
+
+        Ok(Self { inner })
+    }
+
+    pub fn from_bytes(key: &[u8]) -> Result<Self, OlmError> {
+        let bytes: &[u8; vodozemac::Ed25519PublicKey::LENGTH] = key.try_into().map_err(|_| {
+            OlmError::Key(
+                "ed25519",
+                KeyError::InvalidKeyLength {
+                    key_type: "ed25519",
+                    expected_length: vodozemac::Ed25519PublicKey::LENGTH,
+                    length: key.len(),
+                },
+            )
+        })?;
+        let inner = vodozemac::Ed25519PublicKey::from_slice(bytes).map_err(OlmError::key("ed25519"))?;
+
+        Ok(Self { inner })
+    }
+
+    pub fn to_base64(&self) -> String {
+        self.inner.to_base64()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.inner.as_bytes()
+    }
+
+    /// Checks `signature` over `message`, returns an error if it doesn't match.
+    pub fn verify(&self, message: &[u8], signature: &Ed25519Signature) -> Result<(), OlmError> {
+        Ok(self.inner.verify(message, signature)?)
+    }
+
+    /// Checks the `signatures.<user_id>.ed25519:<device_id>` signature of the
+    /// JSON object `json` against its canonical form.
+    ///
+    /// Cross-signing keys are named after their public key instead of a
+    /// device, pass the unpadded base64 key as `device_id` for those.
+    pub fn verify_json(&self, json: &str, user_id: &str, device_id: &str) -> Result<(), OlmError> {
+        let object = canonical_json::parse_object(json)?;
+        let key_id = canonical_json::ed25519_key_id(device_id);
+
+        let signature = object
+            .get("signatures")
+            .and_then(|signatures| signatures.get(user_id))
+            .and_then(|signatures| signatures.get(&key_id))
+            .and_then(|signature| signature.as_str())
+            .ok_or_else(|| OlmError::MissingSignature { user_id: user_id.to_owned(), key_id })?;
+        let signature = Ed25519Signature::from_base64(signature)?;
+
+        self.verify(canonical_json::to_canonical_json(&object)?.as_bytes(), &signature)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Ed25519PublicKey__1from_1base64(
+    mut env: JNIEnv,
+    _class: JClass,
+    key: JString,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let key = Ed25519PublicKey::from_base64(&jstring_to_string(env, key)?)?;
+
+        Ok(handles::insert(key))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Ed25519PublicKey__1from_1bytes(
+    mut env: JNIEnv,
+    _class: JClass,
+    key: JByteArray,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let key = Ed25519PublicKey::from_bytes(&env.convert_byte_array(key)?)?;
+
+        Ok(handles::insert(key))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Ed25519PublicKey__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        handles::free_handle::<Ed25519PublicKey>(my_ptr);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Ed25519PublicKey__1to_1base64(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jstring {
+    jni_call(&mut env, |env| {
+        let key = handles::get::<Ed25519PublicKey>(my_ptr)?;
+        let key = key.lock().to_base64();
+
+        Ok(env.new_string(key)?.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Ed25519PublicKey__1to_1bytes(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jbyteArray {
+    jni_call(&mut env, |env| {
+        let key = handles::get::<Ed25519PublicKey>(my_ptr)?;
+        let bytes = env.byte_array_from_slice(key.lock().as_bytes())?;
+
+        Ok(bytes.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Ed25519PublicKey__1verify(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    message: JByteArray,
+    signature: JString,
+) {
+    jni_call(&mut env, |env| {
+        let key = handles::get::<Ed25519PublicKey>(my_ptr)?;
+        let message = env.convert_byte_array(message)?;
+        let signature = Ed25519Signature::from_base64(&jstring_to_string(env, signature)?)?;
+
+        key.lock().verify(&message, &signature)?;
+
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Ed25519PublicKey__1verify_1bytes(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    message: JByteArray,
+    signature: JByteArray,
+) {
+    jni_call(&mut env, |env| {
+        let key = handles::get::<Ed25519PublicKey>(my_ptr)?;
+        let message = env.convert_byte_array(message)?;
+        let signature = Ed25519Signature::from_slice(&env.convert_byte_array(signature)?)?;
+
+        key.lock().verify(&message, &signature)?;
+
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_Ed25519PublicKey__1verify_1json(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    json: JString,
+    user_id: JString,
+    device_id: JString,
+) {
+    jni_call(&mut env, |env| {
+        let key = handles::get::<Ed25519PublicKey>(my_ptr)?;
+        let json = jstring_to_string(env, json)?;
+        let user_id = jstring_to_string(env, user_id)?;
+        let device_id = jstring_to_string(env, device_id)?;
+
+        key.lock().verify_json(&json, &user_id, &device_id)?;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+
+    #[test]
+    fn verifies_account_signatures() {
+        let account = Account::new();
+        let signature = Ed25519Signature::from_base64(&account.sign("message".to_owned())).unwrap();
+
+        let key = Ed25519PublicKey::from_base64(&account.ed25519_key()).unwrap();
+
+        assert!(key.verify(b"message", &signature).is_ok());
+        assert!(matches!(key.verify(b"other message", &signature), Err(OlmError::Signature(_))));
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let account = Account::new();
+        let key = Ed25519PublicKey::from_base64(&account.ed25519_key()).unwrap();
+
+        let restored = Ed25519PublicKey::from_bytes(key.as_bytes()).unwrap();
+
+        assert_eq!(restored.to_base64(), account.ed25519_key());
+        assert!(matches!(Ed25519PublicKey::from_bytes(&[0u8; 31]), Err(OlmError::Key("ed25519", _))));
+        assert!(matches!(Ed25519PublicKey::from_base64("not a key"), Err(OlmError::Key("ed25519", _))));
+    }
+}
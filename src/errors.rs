@@ -0,0 +1,253 @@
+//! Errors returned by the bindings and the Java exception each one maps to.
+//!
+//! Every variant wraps the concrete vodozemac error, so the Java side gets a
+//! dedicated `OlmException` subclass (with structured fields where vodozemac
+//! provides them) instead of having to string-match a message.
+
+use std::fmt;
+use std::num::TryFromIntError;
+use std::string::FromUtf8Error;
+
+use jni::objects::{JObject, JThrowable, JValue};
+use jni::JNIEnv;
+
+#[derive(Debug)]
+pub enum OlmError {
+    InvalidHandle,
+    InvalidArgument(String),
+    InvalidPickleKey,
+    InvalidPickleKeyHeader,
+    InvalidPickle(String),
+    Pickle(vodozemac::PickleError),
+    LibolmPickle(vodozemac::LibolmPickleError),
+    DehydratedDevice(vodozemac::DehydratedDeviceError),
+    Key(&'static str, vodozemac::KeyError),
+    SessionKey(vodozemac::megolm::SessionKeyDecodeError),
+    Decode(vodozemac::DecodeError),
+    Base64(vodozemac::Base64DecodeError),
+    SessionCreation(vodozemac::olm::SessionCreationError),
+    OlmDecryption(vodozemac::olm::DecryptionError),
+    MegolmDecryption(vodozemac::megolm::DecryptionError),
+    SasMac(vodozemac::sas::SasError),
+    Signature(vodozemac::SignatureError),
+    MissingSignature { user_id: String, key_id: String },
+    NoMatchingSession { sender_key: String },
+    ReplayedMessage { session_id: String, message_index: u32, previous_event_id: String },
+    InvalidEvent(String),
+    RoomIdMismatch { expected: String, actual: Option<String> },
+    Utf8(FromUtf8Error),
+    Json(serde_json::Error),
+    Jni(jni::errors::Error),
+    Other(String),
+}
+
+impl OlmError {
+    /// A public key of the given type couldn't be decoded.
+    pub fn key(key_type: &'static str) -> impl FnOnce(vodozemac::KeyError) -> Self {
+        move |error| OlmError::Key(key_type, error)
+    }
+
+    /// Throws the matching `de.cogia.vodozemac` exception into the JVM.
+    pub fn throw(&self, env: &mut JNIEnv) -> jni::errors::Result<()> {
+        // A failed JNI call already left its own exception pending, keep that one.
+        if env.exception_check()? {
+            return Ok(());
+        }
+
+        let message = env.new_string(self.to_string())?;
+        let message = JValue::from(&message);
+
+        let exception = match self {
+            OlmError::InvalidArgument(_) => env.new_object(
+                "java/lang/IllegalArgumentException",
+                "(Ljava/lang/String;)V",
+                &[message],
+            )?,
+            // Using a closed object is a bug in the caller, not something the
+            // natives declare, so it's unchecked.
+            OlmError::InvalidHandle => env.new_object(
+                "java/lang/IllegalStateException",
+                "(Ljava/lang/String;)V",
+                &[message],
+            )?,
+            OlmError::InvalidPickleKey
+            | OlmError::InvalidPickleKeyHeader
+            | OlmError::InvalidPickle(_)
+            | OlmError::Pickle(_)
+            | OlmError::LibolmPickle(_)
+            | OlmError::DehydratedDevice(_) => {
+                new_exception(env, "PickleException", message)?
+            }
+            OlmError::Key(key_type, _) => {
+                let key_type = env.new_string(key_type)?;
+                env.new_object(
+                    "de/cogia/vodozemac/KeyDecodeException",
+                    "(Ljava/lang/String;Ljava/lang/String;)V",
+                    &[message, (&key_type).into()],
+                )?
+            }
+            OlmError::SessionKey(_) => {
+                let key_type = env.new_string("megolm session key")?;
+                env.new_object(
+                    "de/cogia/vodozemac/KeyDecodeException",
+                    "(Ljava/lang/String;Ljava/lang/String;)V",
+                    &[message, (&key_type).into()],
+                )?
+            }
+            OlmError::Decode(_) | OlmError::Base64(_) | OlmError::InvalidEvent(_) => {
+                new_exception(env, "MessageDecodeException", message)?
+            }
+            OlmError::SessionCreation(vodozemac::olm::SessionCreationError::Decryption(_))
+            | OlmError::OlmDecryption(_)
+            | OlmError::NoMatchingSession { .. } => new_exception(env, "OlmDecryptionException", message)?,
+            OlmError::SessionCreation(_) => {
+                new_exception(env, "OlmSessionCreationException", message)?
+            }
+            OlmError::MegolmDecryption(vodozemac::megolm::DecryptionError::UnknownMessageIndex(
+                first_known_index,
+                message_index,
+            )) => env.new_object(
+                "de/cogia/vodozemac/MegolmUnknownIndexException",
+                "(Ljava/lang/String;JJ)V",
+                &[
+                    message,
+                    JValue::Long(*first_known_index as i64),
+                    JValue::Long(*message_index as i64),
+                ],
+            )?,
+            OlmError::ReplayedMessage { session_id, message_index, previous_event_id } => {
+                let session_id = env.new_string(session_id)?;
+                let previous_event_id = env.new_string(previous_event_id)?;
+                env.new_object(
+                    "de/cogia/vodozemac/MegolmReplayException",
+                    "(Ljava/lang/String;Ljava/lang/String;JLjava/lang/String;)V",
+                    &[
+                        message,
+                        (&session_id).into(),
+                        JValue::Long(*message_index as i64),
+                        (&previous_event_id).into(),
+                    ],
+                )?
+            }
+            OlmError::RoomIdMismatch { expected, actual } => {
+                let expected = env.new_string(expected)?;
+                let actual = match actual {
+                    Some(actual) => env.new_string(actual)?.into(),
+                    None => JObject::null(),
+                };
+                env.new_object(
+                    "de/cogia/vodozemac/RoomIdMismatchException",
+                    "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)V",
+                    &[message, (&expected).into(), (&actual).into()],
+                )?
+            }
+            OlmError::MegolmDecryption(_) => {
+                new_exception(env, "MegolmDecryptionException", message)?
+            }
+            OlmError::SasMac(_) => new_exception(env, "SasMacMismatchException", message)?,
+            OlmError::Signature(_) | OlmError::MissingSignature { .. } => {
+                new_exception(env, "InvalidSignatureException", message)?
+            }
+            OlmError::Utf8(_)
+            | OlmError::Json(_)
+            | OlmError::Jni(_)
+            | OlmError::Other(_) => new_exception(env, "OlmException", message)?,
+        };
+
+        env.throw(JThrowable::from(exception))
+    }
+}
+
+fn new_exception<'a>(
+    env: &mut JNIEnv<'a>,
+    class: &str,
+    message: JValue,
+) -> jni::errors::Result<JObject<'a>> {
+    env.new_object(
+        format!("de/cogia/vodozemac/{class}"),
+        "(Ljava/lang/String;)V",
+        &[message],
+    )
+}
+
+impl fmt::Display for OlmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OlmError::InvalidHandle => write!(f, "invalid or closed handle"),
+            OlmError::InvalidArgument(message) => write!(f, "{message}"),
+            OlmError::InvalidPickleKey => write!(f, "Invalid pickle key length, expected 32 bytes"),
+            OlmError::InvalidPickleKeyHeader => write!(f, "Invalid pickle key header"),
+            OlmError::InvalidPickle(message) => write!(f, "{message}"),
+            OlmError::Pickle(error) => write!(f, "{error}"),
+            OlmError::LibolmPickle(error) => write!(f, "{error}"),
+            OlmError::DehydratedDevice(error) => write!(f, "{error}"),
+            OlmError::Key(_, error) => write!(f, "{error}"),
+            OlmError::SessionKey(error) => write!(f, "{error}"),
+            OlmError::Decode(error) => write!(f, "{error}"),
+            OlmError::Base64(error) => write!(f, "{error}"),
+            OlmError::SessionCreation(error) => write!(f, "{error}"),
+            OlmError::OlmDecryption(error) => write!(f, "{error}"),
+            OlmError::MegolmDecryption(error) => write!(f, "{error}"),
+            OlmError::SasMac(error) => write!(f, "{error}"),
+            OlmError::Signature(error) => write!(f, "{error}"),
+            OlmError::MissingSignature { user_id, key_id } => {
+                write!(f, "The JSON object has no {key_id} signature of {user_id}")
+            }
+            OlmError::NoMatchingSession { sender_key } => {
+                write!(f, "None of the sessions with {sender_key} could decrypt the message")
+            }
+            OlmError::ReplayedMessage { session_id, message_index, previous_event_id } => write!(
+                f,
+                "Message index {message_index} of session {session_id} was already used by event {previous_event_id}"
+            ),
+            OlmError::InvalidEvent(message) => write!(f, "{message}"),
+            OlmError::RoomIdMismatch { expected, actual: Some(actual) } => {
+                write!(f, "The event was encrypted for room {actual}, not {expected}")
+            }
+            OlmError::RoomIdMismatch { expected, actual: None } => {
+                write!(f, "The event has no room id, expected {expected}")
+            }
+            OlmError::Utf8(error) => write!(f, "{error}"),
+            OlmError::Json(error) => write!(f, "{error}"),
+            OlmError::Jni(error) => write!(f, "{error}"),
+            OlmError::Other(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for OlmError {}
+
+macro_rules! from_error {
+    ($($source:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$source> for OlmError {
+                fn from(error: $source) -> Self {
+                    OlmError::$variant(error)
+                }
+            }
+        )*
+    };
+}
+
+from_error! {
+    vodozemac::PickleError => Pickle,
+    vodozemac::LibolmPickleError => LibolmPickle,
+    vodozemac::DehydratedDeviceError => DehydratedDevice,
+    vodozemac::megolm::SessionKeyDecodeError => SessionKey,
+    vodozemac::DecodeError => Decode,
+    vodozemac::Base64DecodeError => Base64,
+    vodozemac::olm::SessionCreationError => SessionCreation,
+    vodozemac::olm::DecryptionError => OlmDecryption,
+    vodozemac::megolm::DecryptionError => MegolmDecryption,
+    vodozemac::sas::SasError => SasMac,
+    vodozemac::SignatureError => Signature,
+    FromUtf8Error => Utf8,
+    serde_json::Error => Json,
+    jni::errors::Error => Jni,
+}
+
+impl From<TryFromIntError> for OlmError {
+    fn from(_: TryFromIntError) -> Self {
+        OlmError::InvalidArgument("Numeric argument out of range".to_owned())
+    }
+}
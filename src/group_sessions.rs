@@ -1,21 +1,22 @@
-use std::error::Error;
 use jni::JNIEnv;
-use jni::objects::{JClass, JObject, JString, JValue};
-use jni::sys::{jlong, jstring};
-use super::{jstring_to_string, result_or_java_exception, CustomError, SessionConfig};
+use jni::objects::{JByteArray, JByteBuffer, JClass, JObject, JString, JValue};
+use jni::sys::{jboolean, jint, jlong, jstring};
+use super::{advance_buffer, direct_buffer, errors::OlmError, handles, jbytes_to_secret, jni_call, jstring_to_string, megolm_message::MegolmMessage, pickle_key::PickleKey, session_config::MegolmSessionConfig};
 
-use vodozemac::megolm::{ExportedSessionKey, MegolmMessage, SessionKey};
+use std::io::Write;
+
+use serde::Deserialize;
+use vodozemac::megolm::{ExportedSessionKey, SessionKey};
+use zeroize::Zeroizing;
 
 pub struct GroupSession {
     pub(super) inner: vodozemac::megolm::GroupSession,
 }
 
 impl GroupSession {
-    pub fn new(config: &mut SessionConfig) -> Self {
-        let _config = if config.version() == 2 { vodozemac::megolm::SessionConfig::version_2() } else { vodozemac::megolm::SessionConfig::version_1() };
-
+    pub fn new(config: &MegolmSessionConfig) -> Self {
         Self {
-            inner: vodozemac::megolm::GroupSession::new(_config),
+            inner: vodozemac::megolm::GroupSession::new(config.inner),
         }
     }
 
@@ -23,6 +24,10 @@ impl GroupSession {
         self.inner.session_id()
     }
 
+    pub fn session_config(&self) -> MegolmSessionConfig {
+        self.inner.session_config().into()
+    }
+
     pub fn session_key(&self) -> String {
         self.inner.session_key().to_base64()
     }
@@ -31,30 +36,27 @@ impl GroupSession {
         self.inner.message_index()
     }
 
-    pub fn encrypt(&mut self, plaintext: String) -> String {
-        self.inner.encrypt(&plaintext).to_base64()
+    pub fn encrypt(&mut self, plaintext: impl AsRef<[u8]>) -> MegolmMessage {
+        self.inner.encrypt(plaintext).into()
     }
 
-    pub fn pickle(&self, pickle_key: String) -> Result<String, Box<dyn Error>> {
-        let pickle_key: &[u8; 32] = pickle_key
-            .as_bytes()
-            .try_into()
-            .map_err(|_| Box::new(CustomError("Invalid pickle key length, expected 32 bytes".to_owned())))?;
-
-        Ok(self.inner.pickle().encrypt(pickle_key))
+    pub fn pickle(&self, pickle_key: &PickleKey) -> String {
+        self.inner.pickle().encrypt(pickle_key.as_bytes())
     }
-    pub fn from_pickle(pickle: String, pickle_key: String) -> Result<GroupSession, Box<dyn Error>> {
-        let pickle_key: &[u8; 32] = pickle_key
-            .as_bytes()
-            .try_into()
-            .map_err(|_| Box::new(CustomError("Invalid pickle key length, expected 32 bytes".to_owned())))?;
-        let pickle = vodozemac::megolm::GroupSessionPickle::from_encrypted(&pickle, pickle_key)
-            .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+    pub fn from_pickle(pickle: String, pickle_key: &PickleKey) -> Result<GroupSession, OlmError> {
+        let pickle = vodozemac::megolm::GroupSessionPickle::from_encrypted(&pickle, pickle_key.as_bytes())?;
 
         let session = vodozemac::megolm::GroupSession::from_pickle(pickle);
 
         Ok(Self { inner: session })
     }
+
+    pub fn from_libolm_pickle(pickle: String, pickle_key: &[u8]) -> Result<GroupSession, OlmError> {
+        // vodozemac marks the session as Megolm v1, the only version libolm had.
+        let inner = vodozemac::megolm::GroupSession::from_libolm_pickle(&pickle, pickle_key)?;
+
+        Ok(Self { inner })
+    }
 }
 
 pub struct DecryptedMessage {
@@ -62,26 +64,70 @@ pub struct DecryptedMessage {
     pub message_index: u32,
 }
 
+/// How an inbound session relates to another one with the same session id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionOrdering {
+    Equal,
+    /// The session can decrypt older messages than the other one.
+    Better,
+    Worse,
+    /// The sessions don't share a ratchet, one of them is a forgery.
+    Unconnected,
+}
+
+impl From<vodozemac::megolm::SessionOrdering> for SessionOrdering {
+    fn from(ordering: vodozemac::megolm::SessionOrdering) -> Self {
+        match ordering {
+            vodozemac::megolm::SessionOrdering::Equal => SessionOrdering::Equal,
+            vodozemac::megolm::SessionOrdering::Better => SessionOrdering::Better,
+            vodozemac::megolm::SessionOrdering::Worse => SessionOrdering::Worse,
+            vodozemac::megolm::SessionOrdering::Unconnected => SessionOrdering::Unconnected,
+        }
+    }
+}
+
+impl From<SessionOrdering> for jint {
+    /// The codes of `SessionOrdering` on the Java side.
+    fn from(ordering: SessionOrdering) -> Self {
+        match ordering {
+            SessionOrdering::Equal => 0,
+            SessionOrdering::Better => 1,
+            SessionOrdering::Worse => 2,
+            SessionOrdering::Unconnected => 3,
+        }
+    }
+}
+
 pub struct InboundGroupSession {
     pub(super) inner: vodozemac::megolm::InboundGroupSession,
+    // vodozemac doesn't expose the config of an inbound session, so remember it.
+    config: MegolmSessionConfig,
 }
 
 impl InboundGroupSession {
-    pub fn new(session_key: String, session_config: &SessionConfig) -> Result<InboundGroupSession, Box<dyn Error>> {
-        let key = SessionKey::from_base64(&session_key).map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
-        let config = if session_config.version() == 2 { vodozemac::megolm::SessionConfig::version_2() } else { vodozemac::megolm::SessionConfig::version_1() };
+    pub fn new(session_key: String, session_config: &MegolmSessionConfig) -> Result<InboundGroupSession, OlmError> {
+        let key = SessionKey::from_base64(&session_key)?;
+
         Ok(Self {
-            inner: vodozemac::megolm::InboundGroupSession::new(&key, config),
+            inner: vodozemac::megolm::InboundGroupSession::new(&key, session_config.inner),
+            config: *session_config,
         })
     }
-    pub fn import(session_key: String, session_config: &SessionConfig) -> Result<InboundGroupSession, Box<dyn Error>> {
-
-        let config = if session_config.version() == 2 { vodozemac::megolm::SessionConfig::version_2() } else { vodozemac::megolm::SessionConfig::version_1() };
+    /// The inbound session our own device needs to decrypt what `session`
+    /// encrypts, starting at its current message index.
+    pub fn from_group_session(session: &GroupSession) -> Self {
+        Self {
+            inner: vodozemac::megolm::InboundGroupSession::from(&session.inner),
+            config: session.session_config(),
+        }
+    }
 
-        let key = ExportedSessionKey::from_base64(&session_key).map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+    pub fn import(session_key: String, session_config: &MegolmSessionConfig) -> Result<InboundGroupSession, OlmError> {
+        let key = ExportedSessionKey::from_base64(&session_key)?;
 
         Ok(Self {
-            inner: vodozemac::megolm::InboundGroupSession::import(&key, config),
+            inner: vodozemac::megolm::InboundGroupSession::import(&key, session_config.inner),
+            config: *session_config,
         })
     }
 
@@ -89,51 +135,121 @@ impl InboundGroupSession {
         self.inner.session_id()
     }
 
+    pub fn session_config(&self) -> MegolmSessionConfig {
+        self.config
+    }
+
     pub fn first_known_index(&self) -> u32 {
         self.inner.first_known_index()
     }
 
-    pub fn export_at(&mut self, index: u32) -> Option<String> {
-        self.inner.export_at(index).map(|k| k.to_base64())
+    /// Whether both sessions were created from the same `GroupSession`,
+    /// possibly at different message indices.
+    pub fn connected(&mut self, other: &mut InboundGroupSession) -> bool {
+        self.inner.connected(&mut other.inner)
     }
 
-    pub fn decrypt(&mut self, ciphertext: String) -> Result<DecryptedMessage, Box<dyn Error>> {
-        let message = MegolmMessage::from_base64(&ciphertext).map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
-        let ret = self.inner.decrypt(&message).map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+    pub fn compare(&mut self, other: &mut InboundGroupSession) -> SessionOrdering {
+        self.inner.compare(&mut other.inner).into()
+    }
+
+    /// Combines the lower first known index of the two sessions with the
+    /// better trust of the two, `None` if they aren't connected.
+    pub fn merge(&mut self, other: &mut InboundGroupSession) -> Option<InboundGroupSession> {
+        let inner = self.inner.merge(&mut other.inner)?;
+
+        Some(Self { inner, config: self.config })
+    }
+
+    /// A copy of the session, vodozemac only allows that through a pickle.
+    fn duplicate(&self) -> InboundGroupSession {
+        Self {
+            inner: vodozemac::megolm::InboundGroupSession::from_pickle(self.inner.pickle()),
+            config: self.config,
+        }
+    }
+
+    pub fn export_at(&mut self, index: u32) -> Result<String, OlmError> {
+        self.inner
+            .export_at(index)
+            .map(|k| k.to_base64())
+            .ok_or_else(|| OlmError::Other(format!("The session can't be exported at message index {index}")))
+    }
+
+    pub fn decrypt(&mut self, ciphertext: String) -> Result<DecryptedMessage, OlmError> {
+        let ret = self.decrypt_bytes(ciphertext)?;
 
         Ok(DecryptedMessage {
-            plaintext: String::from_utf8(ret.plaintext).unwrap(),
+            plaintext: String::from_utf8(ret.plaintext)?,
             message_index: ret.message_index,
         })
     }
-    pub fn pickle(&self, pickle_key: &[u8]) -> Result<String, Box<dyn Error>> {
-        let pickle_key: &[u8; 32] = pickle_key
-            .try_into()
-            .map_err(|_| Box::new(CustomError("Invalid pickle key length, expected 32 bytes".to_owned())))?;
 
-        Ok(self.inner.pickle().encrypt(pickle_key))
+    pub fn decrypt_bytes(
+        &mut self,
+        ciphertext: String,
+    ) -> Result<vodozemac::megolm::DecryptedMessage, OlmError> {
+        let message = vodozemac::megolm::MegolmMessage::from_base64(&ciphertext)?;
+
+        Ok(self.inner.decrypt(&message)?)
+    }
+    pub fn pickle(&self, pickle_key: &PickleKey) -> String {
+        self.inner.pickle().encrypt(pickle_key.as_bytes())
     }
-    pub fn from_pickle(pickle: String, pickle_key: String) -> Result<InboundGroupSession, Box<dyn Error>> {
-        let pickle_key: &[u8; 32] = pickle_key
-            .as_bytes()
-            .try_into()
-            .map_err(|_| Box::new(CustomError("Invalid pickle key length, expected 32 bytes".to_owned())))?;
+    pub fn from_pickle(pickle: String, pickle_key: &PickleKey) -> Result<InboundGroupSession, OlmError> {
         let pickle =
-            vodozemac::megolm::InboundGroupSessionPickle::from_encrypted(&pickle, pickle_key)
-                .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+            vodozemac::megolm::InboundGroupSessionPickle::from_encrypted(&pickle, pickle_key.as_bytes())?;
+        let config = pickled_config(&pickle)?;
 
         let session = vodozemac::megolm::InboundGroupSession::from_pickle(pickle);
 
-        Ok(Self { inner: session })
+        Ok(Self { inner: session, config })
     }
     pub fn from_libolm_pickle(
         pickle: String,
-        pickle_key: String,
-    ) -> Result<InboundGroupSession, Box<dyn Error>> {
-        let inner = vodozemac::megolm::InboundGroupSession::from_libolm_pickle(&pickle, &pickle_key.as_bytes())
-            .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+        pickle_key: &[u8],
+    ) -> Result<InboundGroupSession, OlmError> {
+        let inner = vodozemac::megolm::InboundGroupSession::from_libolm_pickle(&pickle, pickle_key)?;
 
-        Ok(Self { inner })
+        // libolm only ever spoke Megolm v1.
+        Ok(Self { inner, config: MegolmSessionConfig::version_1() })
+    }
+}
+
+/// The only field of an inbound group session pickle we need, serde skips the
+/// ratchet and signing key.
+#[derive(Deserialize)]
+struct PickledConfig {
+    config: vodozemac::megolm::SessionConfig,
+}
+
+/// Reads the config out of a decrypted pickle, the only place vodozemac keeps
+/// it for inbound sessions.
+fn pickled_config(
+    pickle: &vodozemac::megolm::InboundGroupSessionPickle,
+) -> Result<MegolmSessionConfig, OlmError> {
+    // The JSON holds the ratchet, so it is written into a buffer of the exact
+    // size, a growing one would leave copies behind that are never wiped.
+    let mut length = ByteCounter(0);
+    serde_json::to_writer(&mut length, pickle)?;
+    let mut json = Zeroizing::new(Vec::with_capacity(length.0));
+    serde_json::to_writer(&mut *json, pickle)?;
+
+    let pickle: PickledConfig = serde_json::from_slice(&json)?;
+
+    Ok(pickle.config.into())
+}
+
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0 += bytes.len();
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
 
@@ -144,8 +260,25 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1new(
     _class: JClass,
     config: jlong,
 ) -> jlong {
-    let session_config = unsafe { &mut *(config as *mut SessionConfig) };
-    Box::into_raw(Box::new(GroupSession::new(session_config))) as jlong
+    jni_call(&mut env, |_| {
+        let session_config = handles::get::<MegolmSessionConfig>(config)?;
+        let session = GroupSession::new(&session_config.lock());
+
+        Ok(handles::insert(session))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        // Once the last reference is gone, vodozemac's `ZeroizeOnDrop` impls wipe the key material.
+        handles::free_handle::<GroupSession>(my_ptr);
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -154,14 +287,12 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1session_1id(
     _class: JClass,
     my_ptr: jlong,
 ) -> jstring {
-    let session = unsafe { &mut *(my_ptr as *mut GroupSession) };
-
-    // Convert the output Rust String to a new jstring and return it
-    let output_jstring: jstring = **env
-        .new_string(session.session_id())
-        .expect("Failed to create output session_id");
+    jni_call(&mut env, |env| {
+        let session = handles::get::<GroupSession>(my_ptr)?;
+        let session_id = session.lock().session_id();
 
-    output_jstring
+        Ok(env.new_string(session_id)?.into_raw())
+    })
 }
 
 #[no_mangle]
@@ -170,44 +301,91 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1session_1key(
     _class: JClass,
     my_ptr: jlong,
 ) -> jstring {
-    let session = unsafe { &mut *(my_ptr as *mut GroupSession) };
-
-    // Convert the output Rust String to a new jstring and return it
-    let output_jstring: jstring = **env
-        .new_string(session.session_key())
-        .expect("Failed to create output session_key");
+    jni_call(&mut env, |env| {
+        let session = handles::get::<GroupSession>(my_ptr)?;
+        let session_key = session.lock().session_key();
 
-    output_jstring
+        Ok(env.new_string(session_key)?.into_raw())
+    })
 }
 
-// message_index
 #[no_mangle]
 pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1message_1index(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
 ) -> jlong {
-    let session = unsafe { &mut *(my_ptr as *mut GroupSession) };
-    session.message_index() as jlong
-}
+    jni_call(&mut env, |_| {
+        let session = handles::get::<GroupSession>(my_ptr)?;
+        let message_index = session.lock().message_index();
 
+        Ok(message_index as jlong)
+    })
+}
 
 #[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1encrypt(
+pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1session_1config(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
-    message: JString
-) -> jstring {
-    let session = unsafe { &mut *(my_ptr as *mut GroupSession) };
-    let local_message = jstring_to_string(&mut env, message);
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let session = handles::get::<GroupSession>(my_ptr)?;
+        let config = session.lock().session_config();
+
+        Ok(handles::insert(config))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1encrypt<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    message: JString<'a>,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<GroupSession>(my_ptr)?;
+        let local_message = jstring_to_string(env, message)?;
+        let message = session.lock().encrypt(local_message);
+
+        message.into_java(env)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1encrypt_1bytes<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    message: JByteArray<'a>,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<GroupSession>(my_ptr)?;
+        let plaintext = env.convert_byte_array(message)?;
+        let message = session.lock().encrypt(plaintext);
 
-    // Convert the output Rust String to a new jstring and return it
-    let output_jstring: jstring = **env
-        .new_string(session.encrypt(local_message))
-        .expect("Failed to create output session_key");
+        message.into_java(env)
+    })
+}
 
-    output_jstring
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1encrypt_1buffer<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    buffer: JByteBuffer<'a>,
+    offset: jint,
+    length: jint,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<GroupSession>(my_ptr)?;
+        let plaintext = unsafe { direct_buffer(env, &buffer, offset, length)? };
+        let message = session.lock().encrypt(plaintext);
+        advance_buffer(env, &buffer, offset as usize + length as usize)?;
+
+        message.into_java(env)
+    })
 }
 
 #[no_mangle]
@@ -215,25 +393,15 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1pickle(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
-    pswd: JString
+    pickle_key: jlong
 ) -> jstring {
-    let session = unsafe { &mut *(my_ptr as *mut GroupSession) };
-    let local_pswd = jstring_to_string(&mut env, pswd);
+    jni_call(&mut env, |env| {
+        let session = handles::get::<GroupSession>(my_ptr)?;
+        let pickle_key = handles::get::<PickleKey>(pickle_key)?;
+        let pickle = session.lock().pickle(&pickle_key.lock());
 
-    let pickle;
-
-    match result_or_java_exception(&mut env, session.pickle(local_pswd)) {
-        Ok(value) => {
-            pickle = **env
-                .new_string(value)
-                .expect("Failed to create output session_key");
-        }
-        Err(_) => {
-            pickle = std::ptr::null_mut()
-        }
-    }
-
-    pickle
+        Ok(env.new_string(pickle)?.into_raw())
+    })
 }
 
 #[no_mangle]
@@ -241,17 +409,34 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1from_1pickle(
     mut env: JNIEnv,
     _class: JClass,
     pickle: JString,
-    pswd: JString
+    pickle_key: jlong
 ) -> jlong {
+    jni_call(&mut env, |env| {
+        let pickle = jstring_to_string(env, pickle)?;
+        let pickle_key = handles::get::<PickleKey>(pickle_key)?;
 
-    let pickle = jstring_to_string(&mut env, pickle);
-    let pickle_pswd = jstring_to_string(&mut env, pswd);
-
-    let group = GroupSession::from_pickle(pickle, pickle_pswd);
+        let group = GroupSession::from_pickle(pickle, &pickle_key.lock())?;
 
-    Box::into_raw(Box::new(group)) as jlong
+        Ok(handles::insert(group))
+    })
 }
 
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1from_1libolm_1pickle(
+    mut env: JNIEnv,
+    _class: JClass,
+    pickle: JString,
+    pswd: JByteArray
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let pickle = jstring_to_string(env, pickle)?;
+        let pickle_pswd = jbytes_to_secret(env, pswd)?;
+
+        let group = GroupSession::from_libolm_pickle(pickle, &pickle_pswd)?;
+
+        Ok(handles::insert(group))
+    })
+}
 
 #[no_mangle]
 pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1new(
@@ -260,36 +445,56 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1new(
     session_key: JString,
     config: jlong,
 ) -> jlong {
-    let session_config = unsafe { &mut *(config as *mut SessionConfig) };
-    let session_key_local = jstring_to_string(&mut env, session_key);
-    Box::into_raw(Box::new(InboundGroupSession::new(session_key_local, session_config))) as jlong
+    jni_call(&mut env, |env| {
+        let session_config = handles::get::<MegolmSessionConfig>(config)?;
+        let session_key_local = jstring_to_string(env, session_key)?;
+        let session = InboundGroupSession::new(session_key_local, &session_config.lock())?;
+
+        Ok(handles::insert(session))
+    })
 }
 
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1from_1group_1session(
+    mut env: JNIEnv,
+    _class: JClass,
+    group_session: jlong,
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let group_session = handles::get::<GroupSession>(group_session)?;
+        let session = InboundGroupSession::from_group_session(&group_session.lock());
+
+        Ok(handles::insert(session))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        // Once the last reference is gone, vodozemac's `ZeroizeOnDrop` impls wipe the key material.
+        handles::free_handle::<InboundGroupSession>(my_ptr);
+        Ok(())
+    })
+}
 
 #[no_mangle]
 pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1pickle(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
-    pswd: JString
+    pickle_key: jlong
 ) -> jstring {
-    let session = unsafe { &mut *(my_ptr as *mut InboundGroupSession) };
-    let local_pswd = jstring_to_string(&mut env, pswd);
-
-    let keys;
-
-    match result_or_java_exception(&mut env, session.pickle(local_pswd.as_bytes())) {
-        Ok(value) => {
-            keys = **env
-                .new_string(value)
-                .expect("Failed to create output session_key");
-        }
-        Err(_) => {
-            keys = std::ptr::null_mut()
-        }
-    }
+    jni_call(&mut env, |env| {
+        let session = handles::get::<InboundGroupSession>(my_ptr)?;
+        let pickle_key = handles::get::<PickleKey>(pickle_key)?;
+        let pickle = session.lock().pickle(&pickle_key.lock());
 
-    keys
+        Ok(env.new_string(pickle)?.into_raw())
+    })
 }
 
 #[no_mangle]
@@ -297,15 +502,16 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1from_1pickle(
     mut env: JNIEnv,
     _class: JClass,
     pickle: JString,
-    pswd: JString
+    pickle_key: jlong
 ) -> jlong {
+    jni_call(&mut env, |env| {
+        let pickle = jstring_to_string(env, pickle)?;
+        let pickle_key = handles::get::<PickleKey>(pickle_key)?;
 
-    let pickle = jstring_to_string(&mut env, pickle);
-    let pickle_pswd = jstring_to_string(&mut env, pswd);
+        let group = InboundGroupSession::from_pickle(pickle, &pickle_key.lock())?;
 
-    let group = InboundGroupSession::from_pickle(pickle, pickle_pswd);
-
-    Box::into_raw(Box::new(group)) as jlong
+        Ok(handles::insert(group))
+    })
 }
 
 #[no_mangle]
@@ -313,32 +519,30 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1from_1libolm_
     mut env: JNIEnv,
     _class: JClass,
     pickle: JString,
-    pswd: JString
+    pswd: JByteArray
 ) -> jlong {
+    jni_call(&mut env, |env| {
+        let pickle = jstring_to_string(env, pickle)?;
+        let pickle_pswd = jbytes_to_secret(env, pswd)?;
 
-    let pickle = jstring_to_string(&mut env, pickle);
-    let pickle_pswd = jstring_to_string(&mut env, pswd);
-
-    let group = InboundGroupSession::from_libolm_pickle(pickle, pickle_pswd);
+        let group = InboundGroupSession::from_libolm_pickle(pickle, &pickle_pswd)?;
 
-    Box::into_raw(Box::new(group)) as jlong
+        Ok(handles::insert(group))
+    })
 }
 
-
 #[no_mangle]
 pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1session_1id(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
 ) -> jstring {
-    let session = unsafe { &mut *(my_ptr as *mut InboundGroupSession) };
-
-    // Convert the output Rust String to a new jstring and return it
-    let output_jstring: jstring = **env
-        .new_string(session.session_id())
-        .expect("Failed to create output session_id");
+    jni_call(&mut env, |env| {
+        let session = handles::get::<InboundGroupSession>(my_ptr)?;
+        let session_id = session.lock().session_id();
 
-    output_jstring
+        Ok(env.new_string(session_id)?.into_raw())
+    })
 }
 
 #[no_mangle]
@@ -347,8 +551,26 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1first_1known_
     _class: JClass,
     my_ptr: jlong,
 ) -> jlong {
-    let session = unsafe { &mut *(my_ptr as *mut InboundGroupSession) };
-    session.first_known_index() as jlong
+    jni_call(&mut env, |_| {
+        let session = handles::get::<InboundGroupSession>(my_ptr)?;
+        let first_known_index = session.lock().first_known_index();
+
+        Ok(first_known_index as jlong)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1session_1config(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let session = handles::get::<InboundGroupSession>(my_ptr)?;
+        let config = session.lock().session_config();
+
+        Ok(handles::insert(config))
+    })
 }
 
 #[no_mangle]
@@ -358,18 +580,13 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1import(
     session_key: JString,
     config: jlong,
 ) -> jlong {
-    let session_config = unsafe { &mut *(config as *mut SessionConfig) };
-    let session_key_local = jstring_to_string(&mut env, session_key);
-    let session;
-    match result_or_java_exception(&mut env, InboundGroupSession::import(session_key_local, session_config)) {
-        Ok(value) => {
-            session = value;
-        }
-        Err(_) => {
-            return 0 as jlong;
-        }
-    }
-    Box::into_raw(Box::new(session)) as jlong
+    jni_call(&mut env, |env| {
+        let session_config = handles::get::<MegolmSessionConfig>(config)?;
+        let session_key_local = jstring_to_string(env, session_key)?;
+        let session = InboundGroupSession::import(session_key_local, &session_config.lock())?;
+
+        Ok(handles::insert(session))
+    })
 }
 
 #[no_mangle]
@@ -379,14 +596,80 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1export_1at(
     my_ptr: jlong,
     index: jlong,
 ) -> jstring {
-    let session = unsafe { &mut *(my_ptr as *mut InboundGroupSession) };
+    jni_call(&mut env, |env| {
+        let session = handles::get::<InboundGroupSession>(my_ptr)?;
+        let exported = session.lock().export_at(u32::try_from(index)?)?;
+
+        Ok(env.new_string(exported)?.into_raw())
+    })
+}
+
+/// Runs `f` with two inbound sessions locked.
+fn with_session_pair<R>(
+    my_ptr: jlong,
+    other_ptr: jlong,
+    f: impl FnOnce(&mut InboundGroupSession, &mut InboundGroupSession) -> R,
+) -> Result<R, OlmError> {
+    let session = handles::get::<InboundGroupSession>(my_ptr)?;
+    let other = handles::get::<InboundGroupSession>(other_ptr)?;
+
+    if my_ptr == other_ptr {
+        // Locking the same session twice would deadlock, compare it with a copy.
+        let mut session = session.lock();
+        let mut copy = session.duplicate();
+        return Ok(f(&mut session, &mut copy));
+    }
+
+    // Lock in handle order, so comparing A with B and B with A on two threads
+    // at once can't deadlock.
+    let (mut session, mut other) = if my_ptr < other_ptr {
+        let session = session.lock();
+        (session, other.lock())
+    } else {
+        let other = other.lock();
+        (session.lock(), other)
+    };
+
+    Ok(f(&mut session, &mut other))
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1connected(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    other_ptr: jlong,
+) -> jboolean {
+    jni_call(&mut env, |_| with_session_pair(my_ptr, other_ptr, InboundGroupSession::connected))
+}
 
-    // Convert the output Rust String to a new jstring and return it
-    let output_jstring: jstring = **env
-        .new_string(session.export_at(index as u32).unwrap())
-        .expect("Failed to create output session_id");
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1compare(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    other_ptr: jlong,
+) -> jint {
+    jni_call(&mut env, |_| {
+        let ordering = with_session_pair(my_ptr, other_ptr, InboundGroupSession::compare)?;
+
+        Ok(ordering.into())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1merge(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    other_ptr: jlong,
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let merged = with_session_pair(my_ptr, other_ptr, InboundGroupSession::merge)?;
 
-    output_jstring
+        // Zero is never a valid handle, Java maps it to null.
+        Ok(merged.map_or(0, handles::insert))
+    })
 }
 
 #[no_mangle]
@@ -396,36 +679,225 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1decrypt<'a>(
     my_ptr: jlong,
     chipertext: JString<'a>,
 ) -> JObject<'a> {
-    let session = unsafe { &mut *(my_ptr as *mut InboundGroupSession) };
+    jni_call(&mut env, |env| {
+        let session = handles::get::<InboundGroupSession>(my_ptr)?;
+
+        let chipertext_local = jstring_to_string(env, chipertext)?;
+        let res = session.lock().decrypt(chipertext_local)?;
+
+        let decrypted_message = env.new_string(res.plaintext)?;
+        let decrypted_message_index = res.message_index as jlong;
+
+        let args: &[JValue] = &[
+            (&decrypted_message).into(),
+            (decrypted_message_index).into(),
+        ];
+
+        Ok(env.new_object(
+            "de/cogia/vodozemac/OlmDecryptedMessage",
+            "(Ljava/lang/String;J)V",
+            args
+        )?)
+    })
+}
 
-    let chipertext_local = jstring_to_string(&mut env, chipertext);
-    let res;
-    match result_or_java_exception(&mut env, session.decrypt(chipertext_local)) {
-        Ok(value) => {
-            res = value;
-        }
-        Err(_) => {
-            return JObject::null();
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1decrypt_1bytes<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    chipertext: JString<'a>,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<InboundGroupSession>(my_ptr)?;
+
+        let chipertext_local = jstring_to_string(env, chipertext)?;
+        let res = session.lock().decrypt_bytes(chipertext_local)?;
+
+        let decrypted_message = env.byte_array_from_slice(&res.plaintext)?;
+        let decrypted_message_index = res.message_index as jlong;
+
+        let args: &[JValue] = &[
+            (&decrypted_message).into(),
+            (decrypted_message_index).into(),
+        ];
+
+        Ok(env.new_object(
+            "de/cogia/vodozemac/OlmDecryptedBytes",
+            "([BJ)V",
+            args
+        )?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1decrypt_1buffer(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    chipertext: JString,
+    buffer: JByteBuffer,
+    offset: jint,
+    length: jint,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<InboundGroupSession>(my_ptr)?;
+
+        let chipertext_local = jstring_to_string(env, chipertext)?;
+        let output = unsafe { direct_buffer(env, &buffer, offset, length)? };
+        let res = session.lock().decrypt_bytes(chipertext_local)?;
+
+        let output = output.get_mut(..res.plaintext.len()).ok_or_else(|| {
+            OlmError::InvalidArgument(format!("The plaintext needs {} bytes", res.plaintext.len()))
+        })?;
+        output.copy_from_slice(&res.plaintext);
+        advance_buffer(env, &buffer, offset as usize + res.plaintext.len())?;
+
+        Ok(res.message_index as jlong)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{key, OTHER_KEY, PICKLE_KEY};
+
+    #[test]
+    fn group_session_pickle_round_trip() {
+        let session = GroupSession::new(&MegolmSessionConfig::version_2());
+        let pickle = session.pickle(&key(PICKLE_KEY));
+
+        let unpickled = GroupSession::from_pickle(pickle, &key(PICKLE_KEY)).unwrap();
+
+        assert_eq!(session.session_id(), unpickled.session_id());
+    }
+
+    #[test]
+    fn group_session_from_bad_pickle_fails() {
+        let pickle = GroupSession::new(&MegolmSessionConfig::version_2()).pickle(&key(PICKLE_KEY));
+        let truncated = pickle[..pickle.len() / 2].to_owned();
+
+        assert!(GroupSession::from_pickle(pickle, &key(OTHER_KEY)).is_err());
+        assert!(GroupSession::from_pickle(truncated, &key(PICKLE_KEY)).is_err());
+    }
+
+    #[test]
+    fn group_session_from_libolm_pickle_rejects_other_pickles() {
+        let session = GroupSession::new(&MegolmSessionConfig::version_1());
+        let pickle = session.pickle(&key(PICKLE_KEY));
+        let inbound = InboundGroupSession::new(session.session_key(), &MegolmSessionConfig::version_1()).unwrap();
+
+        assert!(matches!(GroupSession::from_libolm_pickle(pickle, PICKLE_KEY), Err(OlmError::LibolmPickle(_))));
+        assert!(matches!(
+            GroupSession::from_libolm_pickle(inbound.pickle(&key(PICKLE_KEY)), PICKLE_KEY),
+            Err(OlmError::LibolmPickle(_))
+        ));
+    }
+
+    #[test]
+    fn inbound_group_session_pickle_round_trip() {
+        let outbound = GroupSession::new(&MegolmSessionConfig::version_2());
+        let session = InboundGroupSession::new(outbound.session_key(), &MegolmSessionConfig::version_2()).unwrap();
+        let pickle = session.pickle(&key(PICKLE_KEY));
+
+        let unpickled = InboundGroupSession::from_pickle(pickle, &key(PICKLE_KEY)).unwrap();
+
+        assert_eq!(outbound.session_id(), unpickled.session_id());
+    }
+
+    #[test]
+    fn inbound_group_session_from_bad_pickle_fails() {
+        let outbound = GroupSession::new(&MegolmSessionConfig::version_2());
+        let session = InboundGroupSession::new(outbound.session_key(), &MegolmSessionConfig::version_2()).unwrap();
+        let pickle = session.pickle(&key(PICKLE_KEY));
+        let truncated = pickle[..pickle.len() / 2].to_owned();
+
+        assert!(InboundGroupSession::from_pickle(pickle.clone(), &key(OTHER_KEY)).is_err());
+        assert!(InboundGroupSession::from_pickle(truncated, &key(PICKLE_KEY)).is_err());
+        assert!(InboundGroupSession::from_libolm_pickle(pickle, PICKLE_KEY).is_err());
+    }
+
+    #[test]
+    fn inbound_group_session_from_outbound_pickle_fails() {
+        let pickle = GroupSession::new(&MegolmSessionConfig::version_2()).pickle(&key(PICKLE_KEY));
+
+        assert!(InboundGroupSession::from_pickle(pickle, &key(PICKLE_KEY)).is_err());
+    }
+
+    #[test]
+    fn inbound_group_session_config_survives_pickling() {
+        for config in [MegolmSessionConfig::version_1(), MegolmSessionConfig::version_2()] {
+            let outbound = GroupSession::new(&config);
+            let session = InboundGroupSession::new(outbound.session_key(), &outbound.session_config()).unwrap();
+            let pickle = session.pickle(&key(PICKLE_KEY));
+
+            let unpickled = InboundGroupSession::from_pickle(pickle, &key(PICKLE_KEY)).unwrap();
+
+            assert_eq!(outbound.session_config(), config);
+            assert_eq!(unpickled.session_config(), config);
         }
     }
 
-    let decrypted_message = env.new_string(res.plaintext).unwrap();
-    let decrypted_message_index = res.message_index as jlong;
+    #[test]
+    fn binary_plaintext_round_trip() {
+        let plaintext = [0xff, 0x00, 0xfe, 0x80];
+        let mut outbound = GroupSession::new(&MegolmSessionConfig::version_2());
+        let mut session = InboundGroupSession::new(outbound.session_key(), &MegolmSessionConfig::version_2()).unwrap();
 
+        let ciphertext = outbound.encrypt(plaintext).to_base64();
 
-    let java_class = env.find_class("de/cogia/vodozemac/OlmDecryptedMessage").unwrap();
+        assert_eq!(session.decrypt_bytes(ciphertext.clone()).unwrap().plaintext, plaintext);
+        assert!(session.decrypt(ciphertext).is_err());
+    }
 
-    let args: &[JValue] = &[
-        (&decrypted_message).into(),
-        (decrypted_message_index).into(),
-    ];
+    #[test]
+    fn inbound_session_from_our_own_group_session() {
+        let mut outbound = GroupSession::new(&MegolmSessionConfig::version_1());
+        outbound.encrypt("before");
 
-    let java_object = env.new_object(
-        java_class,
-        "(Ljava/lang/String;J)V",
-        args
-    ).unwrap();
+        let mut inbound = InboundGroupSession::from_group_session(&outbound);
+        let message = outbound.encrypt("after");
 
-    java_object.into()
-}
+        assert_eq!(inbound.session_id(), outbound.session_id());
+        assert_eq!(inbound.first_known_index(), 1);
+        assert_eq!(inbound.session_config(), MegolmSessionConfig::version_1());
+        let decrypted = inbound.decrypt(message.to_base64()).unwrap();
+        assert_eq!(decrypted.plaintext, "after");
+        assert_eq!(decrypted.message_index, message.message_index());
+    }
+
+    #[test]
+    fn sessions_are_compared_by_first_known_index() {
+        let outbound = GroupSession::new(&MegolmSessionConfig::version_2());
+        let mut first =
+            InboundGroupSession::new(outbound.session_key(), &MegolmSessionConfig::version_2()).unwrap();
+        let mut later = InboundGroupSession::import(first.export_at(10).unwrap(), &MegolmSessionConfig::version_2())
+            .unwrap();
+        let mut other = InboundGroupSession::from_group_session(&GroupSession::new(&MegolmSessionConfig::version_2()));
+
+        assert!(first.connected(&mut later));
+        assert_eq!(first.compare(&mut later), SessionOrdering::Better);
+        assert_eq!(later.compare(&mut first), SessionOrdering::Worse);
+        assert_eq!(first.compare(&mut first.duplicate()), SessionOrdering::Equal);
+        assert!(!first.connected(&mut other));
+        assert_eq!(first.compare(&mut other), SessionOrdering::Unconnected);
+    }
 
+    #[test]
+    fn merging_keeps_the_lower_index() {
+        let outbound = GroupSession::new(&MegolmSessionConfig::version_1());
+        let mut first =
+            InboundGroupSession::new(outbound.session_key(), &MegolmSessionConfig::version_1()).unwrap();
+        let mut later = InboundGroupSession::import(first.export_at(10).unwrap(), &MegolmSessionConfig::version_1())
+            .unwrap();
+
+        let mut merged = later.merge(&mut first).unwrap();
+
+        assert_eq!(merged.first_known_index(), 0);
+        assert_eq!(merged.session_config(), MegolmSessionConfig::version_1());
+        assert_eq!(merged.compare(&mut first), SessionOrdering::Equal);
+        assert!(later
+            .merge(&mut InboundGroupSession::from_group_session(&GroupSession::new(&MegolmSessionConfig::version_1())))
+            .is_none());
+    }
+}
@@ -0,0 +1,125 @@
+//! Registry for every native object handed out to Java.
+//!
+//! Java never sees a real pointer: a handle is a `jlong` made of a slot index
+//! (low 32 bits) and the slot's generation (high 32 bits). Freeing a handle
+//! bumps the generation, so a stale, zero or forged handle resolves to an
+//! error instead of dangling memory. Entries remember their concrete type and
+//! are downcast on lookup, so passing e.g. an `OlmSession` handle where an
+//! `OlmAccount` is expected is rejected as well.
+
+use std::any::Any;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use jni::sys::jlong;
+
+use super::errors::OlmError;
+
+/// A live object resolved from a handle.
+///
+/// Holds its own reference, so the object stays valid even if Java frees the
+/// handle while a call is still using it.
+pub struct Handle<T> {
+    inner: Arc<Mutex<T>>,
+}
+
+impl<T> Handle<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+struct Slot {
+    generation: u32,
+    value: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+struct Registry {
+    slots: Vec<Slot>,
+    vacant: Vec<u32>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    slots: Vec::new(),
+    vacant: Vec::new(),
+});
+
+fn registry() -> MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn encode(index: u32, generation: u32) -> jlong {
+    (((generation as u64) << 32) | index as u64) as jlong
+}
+
+fn decode(handle: jlong) -> (usize, u32) {
+    let handle = handle as u64;
+    ((handle & 0xffff_ffff) as usize, (handle >> 32) as u32)
+}
+
+impl Registry {
+    fn slot(&self, handle: jlong) -> Option<&Slot> {
+        let (index, generation) = decode(handle);
+        self.slots
+            .get(index)
+            .filter(|slot| slot.generation == generation && slot.value.is_some())
+    }
+}
+
+/// Moves `value` into the registry and returns the handle Java should keep.
+pub fn insert<T: Send + 'static>(value: T) -> jlong {
+    let value: Arc<dyn Any + Send + Sync> = Arc::new(Mutex::new(value));
+    let mut registry = registry();
+
+    if let Some(index) = registry.vacant.pop() {
+        let slot = &mut registry.slots[index as usize];
+        slot.value = Some(value);
+        encode(index, slot.generation)
+    } else {
+        let index = registry.slots.len() as u32;
+        // Generations start at 1 so that a zero handle is never valid.
+        registry.slots.push(Slot { generation: 1, value: Some(value) });
+        encode(index, 1)
+    }
+}
+
+/// Resolves `handle` to the object of type `T` it was created for.
+pub fn get<T: Send + 'static>(handle: jlong) -> Result<Handle<T>, OlmError> {
+    let value = registry()
+        .slot(handle)
+        .and_then(|slot| slot.value.clone())
+        .ok_or(OlmError::InvalidHandle)?;
+
+    let inner = value.downcast::<Mutex<T>>().map_err(|_| OlmError::InvalidHandle)?;
+
+    Ok(Handle { inner })
+}
+
+/// Releases the registry's reference to the object behind `handle`.
+///
+/// The object is dropped as soon as no in-flight call holds it any more.
+pub fn remove<T: Send + 'static>(handle: jlong) -> Result<(), OlmError> {
+    let mut registry = registry();
+
+    match registry.slot(handle) {
+        Some(Slot { value: Some(value), .. }) if value.is::<Mutex<T>>() => {}
+        _ => return Err(OlmError::InvalidHandle),
+    }
+
+    let (index, _) = decode(handle);
+    let slot = &mut registry.slots[index];
+    let value = slot.value.take();
+    slot.generation = slot.generation.wrapping_add(1).max(1);
+    registry.vacant.push(index as u32);
+
+    // Drop outside of the registry lock, zeroizing can take a moment.
+    drop(registry);
+    drop(value);
+
+    Ok(())
+}
+
+/// Releases `handle` for the `_free` export of a Java wrapper.
+pub fn free_handle<T: Send + 'static>(handle: jlong) {
+    // Unknown handles are ignored, this runs on the cleaner thread.
+    let _ = remove::<T>(handle);
+}
@@ -1,20 +1,32 @@
 mod account;
+mod canonical_json;
+mod curve25519;
+mod ed25519;
+mod errors;
+mod handles;
+mod megolm_message;
+mod migration;
+mod olm_message;
+mod one_time_keys;
+mod pickle_key;
+mod replay_guard;
+mod room_events;
 mod session;
+mod session_config;
 mod sas;
 mod group_sessions;
+#[cfg(test)]
+mod test_utils;
 
-use std::{fmt};
-use std::error::Error;
-use jni::descriptors::Desc;
-#[allow(unused_variables)]
-// This is the interface to the JVM that we'll call the majority of our
-// methods on.
-//use jni::*;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
 
 use jni::JNIEnv;
-use jni::objects::{JClass, JObject, JString, JThrowable, JValue};
-use jni::signature::ReturnType::Object;
-use jni::sys::jlong;
+use jni::objects::{JByteArray, JByteBuffer, JClass, JLongArray, JObject, JObjectArray, JString, JValue};
+use jni::sys::{jboolean, jint, jlong, jstring};
+
+use errors::OlmError;
+use zeroize::Zeroizing;
 
 #[no_mangle]
 pub unsafe extern "C" fn Java_de_cogia_vodozemac_internal_Native_keepAlive(
@@ -25,122 +37,153 @@ pub unsafe extern "C" fn Java_de_cogia_vodozemac_internal_Native_keepAlive(
 }
 
 #[repr(C)]
-pub struct SessionConfig {
-    _version: u8,
+pub struct IdentityKeys {
+    pub ed25519: String,
+    pub curve25519: String,
 }
 
+pub fn jstring_to_string(env: &mut JNIEnv, obj: JString) -> Result<String, OlmError> {
+    Ok(env.get_string(&obj)?.into())
+}
 
-impl SessionConfig {
-    /// Get the numeric version of this `SessionConfig`.
-    pub const fn version(&self) -> u8 {
-        self._version
-    }
+/// Copies key material out of a Java `byte[]`, the copy is wiped on drop.
+pub fn jbytes_to_secret(env: &mut JNIEnv, obj: JByteArray) -> Result<Zeroizing<Vec<u8>>, OlmError> {
+    Ok(Zeroizing::new(env.convert_byte_array(obj)?))
+}
 
-    /// Create a `SessionConfig` for the Olm version 1. This version of Olm will
-    /// use AES-256 and HMAC with a truncated MAC to encrypt individual
-    /// messages. The MAC will be truncated to 8 bytes.
-    pub const fn version_1() -> Self {
-        SessionConfig { _version: 1 }
+/// Borrows `length` bytes starting at `offset` of a direct `ByteBuffer`.
+///
+/// # Safety
+///
+/// The slice must not outlive the native call it was borrowed in, and Java
+/// must not touch the buffer while it is alive.
+pub unsafe fn direct_buffer<'b>(
+    env: &JNIEnv,
+    buffer: &JByteBuffer,
+    offset: jint,
+    length: jint,
+) -> Result<&'b mut [u8], OlmError> {
+    let address = env
+        .get_direct_buffer_address(buffer)
+        .map_err(|_| OlmError::InvalidArgument("The ByteBuffer is not direct".to_owned()))?;
+    let capacity = env.get_direct_buffer_capacity(buffer)?;
+    let offset = usize::try_from(offset)?;
+    let length = usize::try_from(length)?;
+
+    match offset.checked_add(length) {
+        Some(end) if end <= capacity => {}
+        _ => return Err(OlmError::InvalidArgument("The range exceeds the ByteBuffer".to_owned())),
     }
 
-    /// Create a `SessionConfig` for the Olm version 2. This version of Olm will
-    /// use AES-256 and HMAC to encrypt individual messages. The MAC won't be
-    /// truncated.
-    pub const fn version_2() -> Self {
-        SessionConfig { _version: 2 }
-    }
+    Ok(std::slice::from_raw_parts_mut(address.add(offset), length))
 }
 
-#[repr(C)]
-pub struct IdentityKeys {
-    pub ed25519: String,
-    pub curve25519: String,
+/// Moves the position of `buffer` past the bytes a native call consumed or
+/// produced, like the `ByteBuffer` methods of the JDK do.
+pub fn advance_buffer(env: &mut JNIEnv, buffer: &JByteBuffer, position: usize) -> Result<(), OlmError> {
+    env.call_method(
+        buffer,
+        "position",
+        "(I)Ljava/nio/Buffer;",
+        &[JValue::Int(jint::try_from(position)?)],
+    )?;
+
+    Ok(())
 }
 
+/// Builds a Java array of `class` with one element per value, converted by
+/// `to_java`.
+pub fn object_array<'a, T>(
+    env: &mut JNIEnv<'a>,
+    class: &str,
+    values: Vec<T>,
+    to_java: impl Fn(T, &mut JNIEnv<'a>) -> Result<JObject<'a>, OlmError>,
+) -> Result<JObjectArray<'a>, OlmError> {
+    let array = env.new_object_array(jint::try_from(values.len())?, class, JObject::null())?;
+
+    for (index, value) in values.into_iter().enumerate() {
+        let object = to_java(value, env)?;
+        env.set_object_array_element(&array, index as jint, &object)?;
+        env.delete_local_ref(object)?;
+    }
 
-pub struct OlmMessage {
-    pub ciphertext: String,
-    pub message_type: u32,
+    Ok(array)
 }
 
-impl OlmMessage {
-
-    pub fn new(message_type: u32, ciphertext: String) -> Self {
-        Self {
-            ciphertext,
-            message_type,
-        }
-    }
+/// Value handed back to the JVM when an export fails. Java never sees it, the
+/// pending exception is thrown as soon as the native method returns.
+pub trait JniDefault {
+    fn jni_default() -> Self;
 }
 
-pub fn jstring_to_string(env: &mut JNIEnv, obj: JString) -> String {
-    env.get_string(&obj).expect("Couldn't get Java string").into()
+impl JniDefault for () {
+    fn jni_default() -> Self {}
 }
 
-// "de/cogia/vodozemac/OlmException"
-pub fn result_or_java_exception<'a, T>(
-    env: &mut JNIEnv<'a>,
-    result: Result<T, Box<dyn Error>>,
-) -> Result<T, Box<dyn Error>>
-where
-    T: 'a,
-{
-    return get_result_or_java_exception(env, result, "de/cogia/vodozemac/OlmException");
+impl JniDefault for jint {
+    fn jni_default() -> Self {
+        0
+    }
 }
 
-pub fn get_result_or_java_exception<'a, T>(
-    env: &mut JNIEnv<'a>,
-    result: Result<T, Box<dyn Error>>,
-    exception_class: &str,
-) -> Result<T, Box<dyn Error>>
-where
-    T: 'a,
-{
-    match result {
-        Ok(value) => Ok(value),
-        Err(error) => {
-            let msg_obj = env.new_string(error.to_string()).unwrap();
-            let obj = env.new_object(
-                exception_class,
-                "(Ljava/lang/String;)V",
-                &[(&msg_obj).into()]
-            ).expect("Couldn't create java.lang.Throwable");
-            let throwable = JThrowable::from(obj);
-            env.throw(throwable).unwrap();
-            Err(Box::new(CustomError(error.to_string().to_owned())))
-        }
+impl JniDefault for jlong {
+    fn jni_default() -> Self {
+        0
     }
 }
 
-#[derive(Debug)]
-struct CustomError(String);
-
-impl fmt::Display for CustomError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+impl JniDefault for jboolean {
+    fn jni_default() -> Self {
+        false
     }
 }
 
-impl Error for CustomError {}
-
-
+impl JniDefault for jstring {
+    fn jni_default() -> Self {
+        std::ptr::null_mut()
+    }
+}
 
-#[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_SessionConfig__1version1() -> jlong {
-    Box::into_raw(Box::new(SessionConfig::version_1())) as jlong
+impl<'a> JniDefault for JObject<'a> {
+    fn jni_default() -> Self {
+        JObject::null()
+    }
 }
 
-#[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_SessionConfig__1version2() -> jlong {
-    Box::into_raw(Box::new(SessionConfig::version_2())) as jlong
+impl<'a> JniDefault for JLongArray<'a> {
+    fn jni_default() -> Self {
+        JLongArray::default()
+    }
 }
 
+/// Runs the body of an exported function.
+///
+/// Every `Java_de_cogia_vodozemac_*` export goes through here: errors are
+/// thrown as the matching `OlmException` subclass and panics as a plain
+/// `OlmException`, instead of unwinding into the JVM, which would take the
+/// whole process down.
+pub fn jni_call<'a, R, F>(env: &mut JNIEnv<'a>, body: F) -> R
+where
+    R: JniDefault,
+    F: FnOnce(&mut JNIEnv<'a>) -> Result<R, OlmError>,
+{
+    let error = match panic::catch_unwind(AssertUnwindSafe(|| body(&mut *env))) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(error)) => error,
+        Err(payload) => OlmError::Other(format!("Rust panic: {}", panic_message(payload.as_ref()))),
+    };
+
+    // If even throwing fails there is nothing left to report to.
+    let _ = error.throw(env);
+    R::jni_default()
+}
 
-#[no_mangle]
-pub unsafe extern "C" fn Java_de_cogia_vodozemac_SessionConfig__1version(mut _env: JNIEnv,
-                                                                       _class: JClass,
-                                                                       counter_ptr: jlong,
-) -> jlong {
-    let counter = &mut *(counter_ptr as *mut SessionConfig);
-    counter.version() as jlong
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
 }
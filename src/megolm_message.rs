@@ -0,0 +1,258 @@
+use jni::JNIEnv;
+use jni::objects::{JByteArray, JClass, JObject, JString, JValue};
+use jni::sys::{jbyteArray, jlong, jstring};
+
+use super::{errors::OlmError, handles, jni_call, jstring_to_string, session_config::MegolmSessionConfig};
+
+/// Megolm v1 truncates the MAC to 8 bytes, v2 keeps all of it.
+const FULL_MAC_LENGTH: usize = 32;
+
+/// An encrypted Megolm message. Its parts can be read without a session, e.g.
+/// to tell whether a session's first known index covers it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MegolmMessage {
+    pub(crate) inner: vodozemac::megolm::MegolmMessage,
+}
+
+impl MegolmMessage {
+    /// Decodes the unpadded base64 `ciphertext` of an `m.megolm.v1.aes-sha2`
+    /// event.
+    pub fn from_base64(message: &str) -> Result<Self, OlmError> {
+        let inner = vodozemac::megolm::MegolmMessage::from_base64(message)?;
+
+        Ok(Self { inner })
+    }
+
+    pub fn from_bytes(message: &[u8]) -> Result<Self, OlmError> {
+        let inner = vodozemac::megolm::MegolmMessage::from_bytes(message)?;
+
+        Ok(Self { inner })
+    }
+
+    /// The Megolm version the message was encrypted with, told apart by the
+    /// length of its MAC.
+    pub fn session_config(&self) -> MegolmSessionConfig {
+        if self.mac().len() == FULL_MAC_LENGTH {
+            MegolmSessionConfig::version_2()
+        } else {
+            MegolmSessionConfig::version_1()
+        }
+    }
+
+    pub fn message_index(&self) -> u32 {
+        self.inner.message_index()
+    }
+
+    pub fn ciphertext(&self) -> &[u8] {
+        self.inner.ciphertext()
+    }
+
+    pub fn mac(&self) -> &[u8] {
+        self.inner.mac()
+    }
+
+    /// The Ed25519 signature of the session over the rest of the message, in
+    /// unpadded base64.
+    pub fn signature(&self) -> String {
+        self.inner.signature().to_base64()
+    }
+
+    pub fn to_base64(&self) -> String {
+        self.inner.to_base64()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.inner.to_bytes()
+    }
+
+    /// Wraps the message in a `de.cogia.vodozemac.MegolmMessage` which owns a
+    /// new handle to it.
+    pub fn into_java<'a>(self, env: &mut JNIEnv<'a>) -> Result<JObject<'a>, OlmError> {
+        let ptr = handles::insert(self);
+
+        let object = env.new_object("de/cogia/vodozemac/MegolmMessage", "(J)V", &[JValue::Long(ptr)]);
+        if object.is_err() {
+            let _ = handles::remove::<MegolmMessage>(ptr);
+        }
+
+        Ok(object?)
+    }
+}
+
+impl From<vodozemac::megolm::MegolmMessage> for MegolmMessage {
+    fn from(inner: vodozemac::megolm::MegolmMessage) -> Self {
+        Self { inner }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmMessage__1from_1base64(
+    mut env: JNIEnv,
+    _class: JClass,
+    message: JString,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let message = MegolmMessage::from_base64(&jstring_to_string(env, message)?)?;
+
+        Ok(handles::insert(message))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmMessage__1from_1bytes(
+    mut env: JNIEnv,
+    _class: JClass,
+    message: JByteArray,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let message = MegolmMessage::from_bytes(&env.convert_byte_array(message)?)?;
+
+        Ok(handles::insert(message))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmMessage__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        handles::free_handle::<MegolmMessage>(my_ptr);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmMessage__1message_1index(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let message = handles::get::<MegolmMessage>(my_ptr)?;
+        let message_index = message.lock().message_index();
+
+        Ok(message_index as jlong)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmMessage__1session_1config(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let message = handles::get::<MegolmMessage>(my_ptr)?;
+        let config = message.lock().session_config();
+
+        Ok(handles::insert(config))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmMessage__1ciphertext(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jbyteArray {
+    jni_call(&mut env, |env| {
+        let message = handles::get::<MegolmMessage>(my_ptr)?;
+        let ciphertext = env.byte_array_from_slice(message.lock().ciphertext())?;
+
+        Ok(ciphertext.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmMessage__1mac(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jbyteArray {
+    jni_call(&mut env, |env| {
+        let message = handles::get::<MegolmMessage>(my_ptr)?;
+        let mac = env.byte_array_from_slice(message.lock().mac())?;
+
+        Ok(mac.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmMessage__1signature(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jstring {
+    jni_call(&mut env, |env| {
+        let message = handles::get::<MegolmMessage>(my_ptr)?;
+        let signature = message.lock().signature();
+
+        Ok(env.new_string(signature)?.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmMessage__1to_1base64(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jstring {
+    jni_call(&mut env, |env| {
+        let message = handles::get::<MegolmMessage>(my_ptr)?;
+        let message = message.lock().to_base64();
+
+        Ok(env.new_string(message)?.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmMessage__1to_1bytes(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jbyteArray {
+    jni_call(&mut env, |env| {
+        let message = handles::get::<MegolmMessage>(my_ptr)?;
+        let bytes = env.byte_array_from_slice(&message.lock().to_bytes())?;
+
+        Ok(bytes.into_raw())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group_sessions::GroupSession;
+
+    #[test]
+    fn message_parts() {
+        let mut session = GroupSession::new(&MegolmSessionConfig::version_2());
+        session.encrypt("first");
+
+        let message = session.encrypt("second");
+
+        assert_eq!(message.message_index(), 1);
+        assert_eq!(message.mac().len(), 32);
+        assert_eq!(message.ciphertext().len(), 16);
+        assert_eq!(MegolmMessage::from_bytes(&message.to_bytes()).unwrap(), message);
+        assert_eq!(MegolmMessage::from_base64(&message.to_base64()).unwrap(), message);
+        assert!(message.to_bytes().ends_with(&vodozemac::base64_decode(message.signature()).unwrap()));
+    }
+
+    #[test]
+    fn megolm_version_is_told_apart() {
+        for config in [MegolmSessionConfig::version_1(), MegolmSessionConfig::version_2()] {
+            let message = GroupSession::new(&config).encrypt("Hello").to_base64();
+
+            assert_eq!(MegolmMessage::from_base64(&message).unwrap().session_config(), config);
+        }
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        assert!(matches!(MegolmMessage::from_base64("not base64!"), Err(OlmError::Decode(_))));
+        assert!(matches!(MegolmMessage::from_bytes(&[3, 0, 1]), Err(OlmError::Decode(_))));
+    }
+}
@@ -0,0 +1,190 @@
+//! One-off migration of a libolm store: every libolm pickle is decrypted with
+//! the old key and pickled again in the vodozemac format under a new key.
+//!
+//! A batch is migrated in a single native call and one broken pickle doesn't
+//! stop the others, the caller gets a result per pickle to decide what to do
+//! with the failed ones.
+
+use jni::JNIEnv;
+use jni::objects::{JByteArray, JClass, JObject, JObjectArray, JString};
+use jni::sys::{jint, jlong, jobjectArray};
+
+use super::{
+    account::Account,
+    errors::OlmError,
+    group_sessions::{GroupSession, InboundGroupSession},
+    handles, jbytes_to_secret, jni_call, object_array,
+    pickle_key::PickleKey,
+    session::Session,
+};
+
+/// The kind of object a libolm pickle holds, libolm pickles don't say.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PickleKind {
+    Account,
+    Session,
+    InboundGroupSession,
+    GroupSession,
+}
+
+impl TryFrom<jint> for PickleKind {
+    type Error = OlmError;
+
+    /// The codes of `LibolmMigration.PickleKind`.
+    fn try_from(code: jint) -> Result<Self, OlmError> {
+        match code {
+            0 => Ok(PickleKind::Account),
+            1 => Ok(PickleKind::Session),
+            2 => Ok(PickleKind::InboundGroupSession),
+            3 => Ok(PickleKind::GroupSession),
+            _ => Err(OlmError::InvalidArgument(format!("Unknown pickle kind {code}"))),
+        }
+    }
+}
+
+/// A successfully migrated pickle.
+#[derive(Debug)]
+pub struct MigratedPickle {
+    /// What the pickle is known by: the Curve25519 key of an account, the
+    /// session id of everything else.
+    pub id: String,
+    pub pickle: String,
+}
+
+pub fn migrate_libolm_pickle(
+    kind: PickleKind,
+    pickle: String,
+    old_key: &[u8],
+    new_key: &PickleKey,
+) -> Result<MigratedPickle, OlmError> {
+    let migrated = match kind {
+        PickleKind::Account => {
+            let account = Account::from_libolm_pickle(pickle, old_key)?;
+            MigratedPickle { id: account.curve25519_key(), pickle: account.pickle(new_key) }
+        }
+        PickleKind::Session => {
+            let session = Session::from_libolm_pickle(pickle, old_key)?;
+            MigratedPickle { id: session.session_id(), pickle: session.pickle(new_key) }
+        }
+        PickleKind::InboundGroupSession => {
+            let session = InboundGroupSession::from_libolm_pickle(pickle, old_key)?;
+            MigratedPickle { id: session.session_id(), pickle: session.pickle(new_key) }
+        }
+        PickleKind::GroupSession => {
+            let session = GroupSession::from_libolm_pickle(pickle, old_key)?;
+            MigratedPickle { id: session.session_id(), pickle: session.pickle(new_key) }
+        }
+    };
+
+    Ok(migrated)
+}
+
+/// Migrates every pickle on its own, the results are in the same order as
+/// `pickles`.
+pub fn migrate_libolm_pickles(
+    kind: PickleKind,
+    pickles: impl IntoIterator<Item = String>,
+    old_key: &[u8],
+    new_key: &PickleKey,
+) -> Vec<Result<MigratedPickle, OlmError>> {
+    pickles
+        .into_iter()
+        .map(|pickle| migrate_libolm_pickle(kind, pickle, old_key, new_key))
+        .collect()
+}
+
+fn result_to_java<'a>(
+    result: Result<MigratedPickle, OlmError>,
+    env: &mut JNIEnv<'a>,
+) -> Result<JObject<'a>, OlmError> {
+    let (id, pickle, error) = match result {
+        Ok(migrated) => (
+            env.new_string(migrated.id)?.into(),
+            env.new_string(migrated.pickle)?.into(),
+            JObject::null(),
+        ),
+        Err(error) => (JObject::null(), JObject::null(), env.new_string(error.to_string())?.into()),
+    };
+
+    Ok(env.new_object(
+        "de/cogia/vodozemac/LibolmMigrationResult",
+        "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)V",
+        &[(&id).into(), (&pickle).into(), (&error).into()],
+    )?)
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_LibolmMigration__1migrate(
+    mut env: JNIEnv,
+    _class: JClass,
+    kind: jint,
+    pickles: JObjectArray,
+    old_key: JByteArray,
+    new_key: jlong,
+) -> jobjectArray {
+    jni_call(&mut env, |env| {
+        let kind = PickleKind::try_from(kind)?;
+        let old_key = jbytes_to_secret(env, old_key)?;
+        let new_key = handles::get::<PickleKey>(new_key)?;
+
+        let length = env.get_array_length(&pickles)?;
+        let mut strings = Vec::with_capacity(usize::try_from(length)?);
+        for index in 0..length {
+            // Drop every local reference right away, a store can have more
+            // pickles than the JVM allows local references.
+            let pickle = JString::from(env.get_object_array_element(&pickles, index)?);
+            strings.push(env.get_string(&pickle)?.into());
+            env.delete_local_ref(pickle)?;
+        }
+
+        let results = migrate_libolm_pickles(kind, strings, &old_key, &new_key.lock());
+
+        Ok(object_array(env, "de/cogia/vodozemac/LibolmMigrationResult", results, result_to_java)?.into_raw())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{key, PICKLE_KEY};
+
+    const OLD_KEY: &[u8] = b"DEFAULT_PICKLE_KEY";
+
+    #[test]
+    fn migrates_accounts_and_reports_failures() {
+        let new_key = key(PICKLE_KEY);
+        let account = Account::new();
+        let libolm_pickle = account.to_libolm_pickle(OLD_KEY).unwrap();
+
+        let results = migrate_libolm_pickles(
+            PickleKind::Account,
+            [libolm_pickle.clone(), "garbage".to_owned(), account.to_libolm_pickle(b"other key").unwrap()],
+            OLD_KEY,
+            &new_key,
+        );
+
+        let migrated = results[0].as_ref().unwrap();
+        assert_eq!(migrated.id, account.curve25519_key());
+        let unpickled = Account::from_pickle(migrated.pickle.clone(), &new_key).unwrap();
+        assert_eq!(unpickled.ed25519_key(), account.ed25519_key());
+
+        assert!(matches!(results[1], Err(OlmError::LibolmPickle(_))));
+        assert!(matches!(results[2], Err(OlmError::LibolmPickle(_))));
+    }
+
+    #[test]
+    fn pickles_of_the_wrong_kind_fail() {
+        let new_key = key(PICKLE_KEY);
+        let libolm_pickle = Account::new().to_libolm_pickle(OLD_KEY).unwrap();
+
+        for kind in [PickleKind::Session, PickleKind::InboundGroupSession, PickleKind::GroupSession] {
+            assert!(migrate_libolm_pickle(kind, libolm_pickle.clone(), OLD_KEY, &new_key).is_err());
+        }
+    }
+
+    #[test]
+    fn pickle_kind_codes() {
+        assert_eq!(PickleKind::try_from(2).unwrap(), PickleKind::InboundGroupSession);
+        assert!(PickleKind::try_from(4).is_err());
+    }
+}
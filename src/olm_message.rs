@@ -0,0 +1,226 @@
+use jni::JNIEnv;
+use jni::objects::{JClass, JObject, JString, JValue};
+use jni::sys::{jint, jlong, jstring};
+use vodozemac::{base64_decode, base64_encode};
+use vodozemac::olm::{MessageType, SessionKeys};
+
+use super::{errors::OlmError, handles, jni_call, jstring_to_string, session::session_keys_object};
+
+/// The codes of `OlmMessage.Type`, they match the `type` field of an
+/// `m.olm.v1.curve25519-aes-sha2` ciphertext.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OlmMessageType {
+    PreKey,
+    Normal,
+}
+
+impl TryFrom<jint> for OlmMessageType {
+    type Error = OlmError;
+
+    fn try_from(code: jint) -> Result<Self, OlmError> {
+        match code {
+            0 => Ok(OlmMessageType::PreKey),
+            1 => Ok(OlmMessageType::Normal),
+            _ => Err(OlmError::InvalidArgument(format!("Unknown Olm message type {code}"))),
+        }
+    }
+}
+
+impl From<OlmMessageType> for jint {
+    fn from(message_type: OlmMessageType) -> Self {
+        match message_type {
+            OlmMessageType::PreKey => 0,
+            OlmMessageType::Normal => 1,
+        }
+    }
+}
+
+/// An Olm message, decoded when it is created so every later use can rely on
+/// it being well formed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OlmMessage {
+    pub(crate) inner: vodozemac::olm::OlmMessage,
+}
+
+impl OlmMessage {
+    /// Decodes the unpadded base64 `ciphertext` of a message of the given type.
+    pub fn from_parts(message_type: OlmMessageType, ciphertext: &str) -> Result<Self, OlmError> {
+        let inner = vodozemac::olm::OlmMessage::from_parts(
+            jint::from(message_type) as usize,
+            &base64_decode(ciphertext)?,
+        )?;
+
+        Ok(Self { inner })
+    }
+
+    pub fn message_type(&self) -> OlmMessageType {
+        match self.inner.message_type() {
+            MessageType::PreKey => OlmMessageType::PreKey,
+            MessageType::Normal => OlmMessageType::Normal,
+        }
+    }
+
+    pub fn ciphertext(&self) -> String {
+        let (_, ciphertext) = self.inner.to_parts();
+
+        base64_encode(ciphertext)
+    }
+
+    /// The length of the encrypted payload. The plaintext is padded before it
+    /// is encrypted, so it is never longer than this.
+    pub fn max_plaintext_length(&self) -> usize {
+        self.inner.message().len()
+    }
+
+    /// The keys of the session a pre-key message wants to establish, normal
+    /// messages don't carry any.
+    pub fn session_keys(&self) -> Option<SessionKeys> {
+        match &self.inner {
+            vodozemac::olm::OlmMessage::PreKey(message) => Some(message.session_keys()),
+            vodozemac::olm::OlmMessage::Normal(_) => None,
+        }
+    }
+
+    /// Wraps the message in a `de.cogia.vodozemac.OlmMessage` which owns a new
+    /// handle to it.
+    pub fn into_java<'a>(self, env: &mut JNIEnv<'a>) -> Result<JObject<'a>, OlmError> {
+        let ptr = handles::insert(self);
+
+        let object = env.new_object("de/cogia/vodozemac/OlmMessage", "(J)V", &[JValue::Long(ptr)]);
+        if object.is_err() {
+            let _ = handles::remove::<OlmMessage>(ptr);
+        }
+
+        Ok(object?)
+    }
+}
+
+impl From<vodozemac::olm::OlmMessage> for OlmMessage {
+    fn from(inner: vodozemac::olm::OlmMessage) -> Self {
+        Self { inner }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmMessage__1from_1parts(
+    mut env: JNIEnv,
+    _class: JClass,
+    message_type: jint,
+    ciphertext: JString,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let message_type = OlmMessageType::try_from(message_type)?;
+        let message = OlmMessage::from_parts(message_type, &jstring_to_string(env, ciphertext)?)?;
+
+        Ok(handles::insert(message))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmMessage__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        handles::free_handle::<OlmMessage>(my_ptr);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmMessage__1message_1type(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jint {
+    jni_call(&mut env, |_| {
+        let message = handles::get::<OlmMessage>(my_ptr)?;
+        let message_type = message.lock().message_type();
+
+        Ok(message_type.into())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmMessage__1ciphertext(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jstring {
+    jni_call(&mut env, |env| {
+        let message = handles::get::<OlmMessage>(my_ptr)?;
+        let ciphertext = message.lock().ciphertext();
+
+        Ok(env.new_string(ciphertext)?.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmMessage__1session_1keys<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let message = handles::get::<OlmMessage>(my_ptr)?;
+        let session_keys = message.lock().session_keys();
+
+        match session_keys {
+            Some(keys) => session_keys_object(env, keys),
+            None => Ok(JObject::null()),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+    use crate::session_config::OlmSessionConfig;
+
+    #[test]
+    fn pre_key_messages_carry_the_session_keys() {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+        let one_time_key = bob.one_time_keys()[0].key.to_base64();
+        let mut session = alice
+            .create_outbound_session(bob.curve25519_key(), one_time_key.clone(), &OlmSessionConfig::version_2())
+            .unwrap();
+
+        let message = session.encrypt("Hello");
+
+        assert_eq!(message.message_type(), OlmMessageType::PreKey);
+        let keys = message.session_keys().unwrap();
+        assert_eq!(keys.identity_key.to_base64(), alice.curve25519_key());
+        assert_eq!(keys.one_time_key.to_base64(), one_time_key);
+        assert_eq!(keys, session.session_keys());
+    }
+
+    #[test]
+    fn parts_round_trip() {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+        let mut session = alice
+            .create_outbound_session(
+                bob.curve25519_key(),
+                bob.one_time_keys()[0].key.to_base64(),
+                &OlmSessionConfig::version_2(),
+            )
+            .unwrap();
+        let message = session.encrypt("Hello");
+
+        let parsed = OlmMessage::from_parts(message.message_type(), &message.ciphertext()).unwrap();
+
+        assert_eq!(parsed, message);
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        assert!(OlmMessageType::try_from(2).is_err());
+        assert!(OlmMessage::from_parts(OlmMessageType::PreKey, "not base64!").is_err());
+        assert!(OlmMessage::from_parts(OlmMessageType::Normal, "AAAA").is_err());
+    }
+}
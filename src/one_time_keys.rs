@@ -0,0 +1,61 @@
+use jni::JNIEnv;
+use jni::objects::{JObject, JObjectArray};
+use vodozemac::KeyId;
+
+use super::{curve25519::Curve25519PublicKey, errors::OlmError, object_array};
+
+/// An unpublished one-time or fallback key of an account together with its
+/// key id, vodozemac doesn't hand out published ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OneTimeKey {
+    pub key_id: KeyId,
+    pub key: Curve25519PublicKey,
+}
+
+impl OneTimeKey {
+    pub fn to_java<'a>(self, env: &mut JNIEnv<'a>) -> Result<JObject<'a>, OlmError> {
+        let key_id = env.new_string(self.key_id.to_base64())?;
+        let key = self.key.to_java(env)?;
+
+        Ok(env.new_object(
+            "de/cogia/vodozemac/OneTimeKey",
+            "(Ljava/lang/String;Lde/cogia/vodozemac/Curve25519PublicKey;)V",
+            &[(&key_id).into(), (&key).into()],
+        )?)
+    }
+}
+
+/// The one-time keys `generate_one_time_keys` created, and the oldest ones it
+/// had to discard to make room for them. vodozemac keeps the private parts of
+/// up to 100 times `max_number_of_one_time_keys` keys, published or not.
+/// Only the public key of a discarded key is known anymore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OneTimeKeyGenerationResult {
+    pub created: Vec<OneTimeKey>,
+    pub removed: Vec<Curve25519PublicKey>,
+}
+
+impl OneTimeKeyGenerationResult {
+    pub fn into_java<'a>(self, env: &mut JNIEnv<'a>) -> Result<JObject<'a>, OlmError> {
+        let created = one_time_keys_to_java(env, self.created)?;
+        let removed = object_array(
+            env,
+            "de/cogia/vodozemac/Curve25519PublicKey",
+            self.removed,
+            Curve25519PublicKey::to_java,
+        )?;
+
+        Ok(env.new_object(
+            "de/cogia/vodozemac/OneTimeKeyGenerationResult",
+            "([Lde/cogia/vodozemac/OneTimeKey;[Lde/cogia/vodozemac/Curve25519PublicKey;)V",
+            &[(&created).into(), (&removed).into()],
+        )?)
+    }
+}
+
+pub fn one_time_keys_to_java<'a>(
+    env: &mut JNIEnv<'a>,
+    keys: Vec<OneTimeKey>,
+) -> Result<JObjectArray<'a>, OlmError> {
+    object_array(env, "de/cogia/vodozemac/OneTimeKey", keys, OneTimeKey::to_java)
+}
@@ -0,0 +1,367 @@
+//! The 32 byte key pickles are encrypted with, either given as raw bytes or
+//! derived from a user passphrase.
+//!
+//! A derived key remembers how it was derived in a small header: a format
+//! version, the KDF, its parameters and the random salt. The header isn't
+//! secret, store it next to the pickles and pass it back together with the
+//! passphrase to get the same key again.
+
+use std::ops::RangeInclusive;
+
+use argon2::Argon2;
+use jni::JNIEnv;
+use jni::objects::{JByteArray, JClass};
+use jni::sys::{jbyteArray, jint, jlong};
+use rand::RngCore;
+use sha2::Sha512;
+use zeroize::Zeroizing;
+
+use super::{errors::OlmError, handles, jbytes_to_secret, jni_call};
+
+const HEADER_VERSION: u8 = 1;
+const SALT_LENGTH: usize = 16;
+
+// The header is stored without any integrity protection, so the cost it asks
+// for is bounded: Argon2 aborts the process if it can't allocate its memory,
+// and PBKDF2 with billions of iterations hangs the caller.
+const PBKDF2_ITERATIONS: RangeInclusive<u32> = 1..=10_000_000;
+const ARGON2_MEMORY_KIB: RangeInclusive<u32> = 8..=256 * 1024;
+const ARGON2_ITERATIONS: RangeInclusive<u32> = 1..=64;
+const ARGON2_PARALLELISM: RangeInclusive<u32> = 1..=16;
+
+/// The key derivation function, and its parameters, a `PickleKey` was derived with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    Pbkdf2Sha512 {
+        iterations: u32,
+    },
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl Kdf {
+    const PBKDF2_SHA512: u8 = 1;
+    const ARGON2ID: u8 = 2;
+
+    fn is_within_bounds(&self) -> bool {
+        match *self {
+            Kdf::Pbkdf2Sha512 { iterations } => PBKDF2_ITERATIONS.contains(&iterations),
+            Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+                ARGON2_MEMORY_KIB.contains(&memory_kib)
+                    && ARGON2_ITERATIONS.contains(&iterations)
+                    && ARGON2_PARALLELISM.contains(&parallelism)
+            }
+        }
+    }
+
+    fn derive(&self, passphrase: &[u8], salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, OlmError> {
+        if !self.is_within_bounds() {
+            return Err(OlmError::InvalidArgument(format!("Unsupported KDF parameters {self:?}")));
+        }
+
+        let mut key = Zeroizing::new([0u8; 32]);
+
+        match *self {
+            Kdf::Pbkdf2Sha512 { iterations } => {
+                pbkdf2::pbkdf2_hmac::<Sha512>(passphrase, salt, iterations, key.as_mut());
+            }
+            Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+                let params = argon2::Params::new(memory_kib, iterations, parallelism, Some(32))
+                    .map_err(|error| OlmError::InvalidArgument(error.to_string()))?;
+
+                Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(passphrase, salt, key.as_mut())
+                    .map_err(|error| OlmError::InvalidArgument(error.to_string()))?;
+            }
+        }
+
+        Ok(key)
+    }
+}
+
+pub struct PickleKey {
+    key: Zeroizing<[u8; 32]>,
+    header: Option<Vec<u8>>,
+}
+
+impl PickleKey {
+    /// Uses `key` as is, it must be exactly 32 bytes long.
+    pub fn from_bytes(key: &[u8]) -> Result<Self, OlmError> {
+        let key: [u8; 32] = key.try_into().map_err(|_| OlmError::InvalidPickleKey)?;
+
+        Ok(Self { key: Zeroizing::new(key), header: None })
+    }
+
+    /// Derives a new key from `passphrase` with a fresh random salt.
+    pub fn derive(passphrase: &[u8], kdf: Kdf) -> Result<Self, OlmError> {
+        let mut salt = [0u8; SALT_LENGTH];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        Self::derive_with_salt(passphrase, kdf, &salt)
+    }
+
+    /// Derives the key described by `header` from `passphrase` again.
+    pub fn from_passphrase(passphrase: &[u8], header: &[u8]) -> Result<Self, OlmError> {
+        let (kdf, salt) = parse_header(header).ok_or(OlmError::InvalidPickleKeyHeader)?;
+
+        Self::derive_with_salt(passphrase, kdf, salt)
+    }
+
+    fn derive_with_salt(passphrase: &[u8], kdf: Kdf, salt: &[u8]) -> Result<Self, OlmError> {
+        let key = kdf.derive(passphrase, salt)?;
+
+        Ok(Self { key, header: Some(encode_header(kdf, salt)) })
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    /// The derivation header, `None` for keys created from raw bytes.
+    pub fn header(&self) -> Option<&[u8]> {
+        self.header.as_deref()
+    }
+}
+
+fn encode_header(kdf: Kdf, salt: &[u8]) -> Vec<u8> {
+    let mut header = vec![HEADER_VERSION];
+
+    match kdf {
+        Kdf::Pbkdf2Sha512 { iterations } => {
+            header.push(Kdf::PBKDF2_SHA512);
+            header.extend_from_slice(&iterations.to_be_bytes());
+        }
+        Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+            header.push(Kdf::ARGON2ID);
+            header.extend_from_slice(&memory_kib.to_be_bytes());
+            header.extend_from_slice(&iterations.to_be_bytes());
+            header.extend_from_slice(&parallelism.to_be_bytes());
+        }
+    }
+
+    header.push(salt.len() as u8);
+    header.extend_from_slice(salt);
+
+    header
+}
+
+fn parse_header(header: &[u8]) -> Option<(Kdf, &[u8])> {
+    let (&version, rest) = header.split_first()?;
+    if version != HEADER_VERSION {
+        return None;
+    }
+
+    let (&algorithm, mut rest) = rest.split_first()?;
+    let mut next_u32 = || {
+        let (value, tail) = rest.split_first_chunk::<4>()?;
+        rest = tail;
+        Some(u32::from_be_bytes(*value))
+    };
+
+    let kdf = match algorithm {
+        Kdf::PBKDF2_SHA512 => Kdf::Pbkdf2Sha512 { iterations: next_u32()? },
+        Kdf::ARGON2ID => Kdf::Argon2id {
+            memory_kib: next_u32()?,
+            iterations: next_u32()?,
+            parallelism: next_u32()?,
+        },
+        _ => return None,
+    };
+    if !kdf.is_within_bounds() {
+        return None;
+    }
+
+    let (&salt_length, salt) = rest.split_first()?;
+    if salt_length as usize != SALT_LENGTH || salt.len() != SALT_LENGTH {
+        return None;
+    }
+
+    Some((kdf, salt))
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_PickleKey__1from_1bytes(
+    mut env: JNIEnv,
+    _class: JClass,
+    key: JByteArray,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let key = PickleKey::from_bytes(&jbytes_to_secret(env, key)?)?;
+
+        Ok(handles::insert(key))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_PickleKey__1derive_1pbkdf2(
+    mut env: JNIEnv,
+    _class: JClass,
+    passphrase: JByteArray,
+    iterations: jint,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let kdf = Kdf::Pbkdf2Sha512 { iterations: u32::try_from(iterations)? };
+        let key = PickleKey::derive(&jbytes_to_secret(env, passphrase)?, kdf)?;
+
+        Ok(handles::insert(key))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_PickleKey__1derive_1argon2id(
+    mut env: JNIEnv,
+    _class: JClass,
+    passphrase: JByteArray,
+    memory_kib: jint,
+    iterations: jint,
+    parallelism: jint,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let kdf = Kdf::Argon2id {
+            memory_kib: u32::try_from(memory_kib)?,
+            iterations: u32::try_from(iterations)?,
+            parallelism: u32::try_from(parallelism)?,
+        };
+        let key = PickleKey::derive(&jbytes_to_secret(env, passphrase)?, kdf)?;
+
+        Ok(handles::insert(key))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_PickleKey__1from_1passphrase(
+    mut env: JNIEnv,
+    _class: JClass,
+    passphrase: JByteArray,
+    header: JByteArray,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let header = env.convert_byte_array(header)?;
+        let key = PickleKey::from_passphrase(&jbytes_to_secret(env, passphrase)?, &header)?;
+
+        Ok(handles::insert(key))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_PickleKey__1header(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jbyteArray {
+    jni_call(&mut env, |env| {
+        let key = handles::get::<PickleKey>(my_ptr)?;
+        let key = key.lock();
+
+        match key.header() {
+            Some(header) => Ok(env.byte_array_from_slice(header)?.into_raw()),
+            None => Ok(std::ptr::null_mut()),
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_PickleKey__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        // `Zeroizing` wipes the key once the last reference is gone.
+        handles::free_handle::<PickleKey>(my_ptr);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PBKDF2: Kdf = Kdf::Pbkdf2Sha512 { iterations: 1000 };
+    const ARGON2ID: Kdf = Kdf::Argon2id { memory_kib: 64, iterations: 1, parallelism: 1 };
+
+    #[test]
+    fn derived_key_can_be_restored_from_its_header() {
+        for kdf in [PBKDF2, ARGON2ID] {
+            let key = PickleKey::derive(b"1234", kdf).unwrap();
+
+            let restored = PickleKey::from_passphrase(b"1234", key.header().unwrap()).unwrap();
+            let other = PickleKey::from_passphrase(b"4321", key.header().unwrap()).unwrap();
+
+            assert_eq!(key.as_bytes(), restored.as_bytes());
+            assert_ne!(key.as_bytes(), other.as_bytes());
+        }
+    }
+
+    #[test]
+    fn every_derivation_uses_a_new_salt() {
+        let first = PickleKey::derive(b"1234", PBKDF2).unwrap();
+        let second = PickleKey::derive(b"1234", PBKDF2).unwrap();
+
+        assert_ne!(first.as_bytes(), second.as_bytes());
+        assert_ne!(first.header(), second.header());
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let salt = [7u8; SALT_LENGTH];
+
+        for kdf in [PBKDF2, ARGON2ID] {
+            let header = encode_header(kdf, &salt);
+
+            assert_eq!(parse_header(&header), Some((kdf, &salt[..])));
+            assert_eq!(parse_header(&header[..header.len() - 1]), None);
+        }
+
+        assert_eq!(parse_header(&[]), None);
+        assert_eq!(parse_header(&[HEADER_VERSION + 1, Kdf::PBKDF2_SHA512, 0, 0, 0, 1, 0]), None);
+        assert_eq!(parse_header(&[HEADER_VERSION, 0xff, 0, 0, 0, 1, 0]), None);
+    }
+
+    #[test]
+    fn headers_with_unbounded_costs_are_rejected() {
+        let salt = [7u8; SALT_LENGTH];
+
+        for kdf in [
+            Kdf::Pbkdf2Sha512 { iterations: u32::MAX },
+            Kdf::Pbkdf2Sha512 { iterations: 0 },
+            Kdf::Argon2id { memory_kib: u32::MAX, iterations: 1, parallelism: 1 },
+            Kdf::Argon2id { memory_kib: 1024 * 1024, iterations: 1, parallelism: 1 },
+            Kdf::Argon2id { memory_kib: 64, iterations: u32::MAX, parallelism: 1 },
+            Kdf::Argon2id { memory_kib: 64, iterations: 1, parallelism: u32::MAX },
+            Kdf::Argon2id { memory_kib: 64, iterations: 0, parallelism: 1 },
+        ] {
+            let header = encode_header(kdf, &salt);
+
+            assert!(matches!(PickleKey::from_passphrase(b"1234", &header), Err(OlmError::InvalidPickleKeyHeader)));
+            assert!(matches!(PickleKey::derive(b"1234", kdf), Err(OlmError::InvalidArgument(_))));
+        }
+    }
+
+    #[test]
+    fn headers_with_another_salt_length_are_rejected() {
+        let kdf = Kdf::Pbkdf2Sha512 { iterations: 1 };
+
+        for salt in [&[][..], &[7u8; SALT_LENGTH - 1], &[7u8; SALT_LENGTH + 1], &[7u8; 255]] {
+            let header = encode_header(kdf, salt);
+
+            assert!(matches!(PickleKey::from_passphrase(b"1234", &header), Err(OlmError::InvalidPickleKeyHeader)));
+        }
+    }
+
+    #[test]
+    fn raw_keys_must_be_32_bytes() {
+        assert!(PickleKey::from_bytes(&[0u8; 32]).unwrap().header().is_none());
+        assert!(PickleKey::from_bytes(&[0u8; 31]).is_err());
+        assert!(PickleKey::from_bytes(&[0u8; 33]).is_err());
+    }
+
+    #[test]
+    fn invalid_kdf_parameters_are_rejected() {
+        assert!(PickleKey::derive(b"1234", Kdf::Pbkdf2Sha512 { iterations: 0 }).is_err());
+        assert!(PickleKey::derive(b"1234", Kdf::Argon2id { memory_kib: 1, iterations: 1, parallelism: 1 }).is_err());
+    }
+}
@@ -0,0 +1,430 @@
+//! Replay protection for Megolm.
+//!
+//! A Megolm message index can only be used once per session, but nothing in a
+//! ciphertext ties it to the event it was sent in, so a server can hand out an
+//! old ciphertext again under a new event id. The guard remembers which event
+//! every decrypted index belonged to and rejects any other event reusing it.
+//! Decrypting the same event again, e.g. after a restart, is fine.
+//!
+//! Records are only dropped on request, so a long-lived guard and its pickle
+//! keep growing. `prune_before` drops the records of old events, after which
+//! a replay of those events can't be told apart from the original anymore.
+//!
+//! The guard holds no key material, but the event ids and timestamps are
+//! metadata, so its pickle is encrypted with the pickle key as well.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use jni::JNIEnv;
+use jni::objects::{JClass, JObject, JString, JValue};
+use jni::sys::{jlong, jstring};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use vodozemac::{base64_decode, base64_encode};
+use zeroize::Zeroizing;
+
+use super::{
+    errors::OlmError, group_sessions::InboundGroupSession, handles, jni_call, jstring_to_string,
+    pickle_key::PickleKey,
+};
+
+const PICKLE_VERSION: u8 = 1;
+const NONCE_LENGTH: usize = 12;
+const PICKLE_KEY_INFO: &[u8] = b"VODOZEMAC_ANDROID_MEGOLM_REPLAY_GUARD";
+
+/// The event a Megolm message was decrypted for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventMetadata {
+    pub event_id: String,
+    /// The `origin_server_ts` of the event.
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ReplayGuard {
+    /// The decrypted message indices of every session id.
+    sessions: HashMap<String, BTreeMap<u32, EventMetadata>>,
+}
+
+#[derive(Deserialize)]
+struct ReplayGuardPickle {
+    version: u8,
+    guard: ReplayGuard,
+}
+
+impl ReplayGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `message_index` of `session_id` was decrypted for `event`.
+    ///
+    /// Fails without recording anything if the index already decrypted
+    /// another event.
+    pub fn record(&mut self, session_id: &str, message_index: u32, event: EventMetadata) -> Result<(), OlmError> {
+        let indices = self.sessions.entry(session_id.to_owned()).or_default();
+
+        match indices.get(&message_index) {
+            Some(previous) if *previous != event => Err(OlmError::ReplayedMessage {
+                session_id: session_id.to_owned(),
+                message_index,
+                previous_event_id: previous.event_id.clone(),
+            }),
+            Some(_) => Ok(()),
+            None => {
+                indices.insert(message_index, event);
+                Ok(())
+            }
+        }
+    }
+
+    /// Decrypts `ciphertext` and records its message index. Only the index of
+    /// a message that decrypted is trusted, so a failed decryption records
+    /// nothing.
+    pub fn decrypt(
+        &mut self,
+        session: &mut InboundGroupSession,
+        ciphertext: String,
+        event: EventMetadata,
+    ) -> Result<vodozemac::megolm::DecryptedMessage, OlmError> {
+        let decrypted = session.decrypt_bytes(ciphertext)?;
+        self.record(&session.session_id(), decrypted.message_index, event)?;
+
+        Ok(decrypted)
+    }
+
+    /// Drops the records of a session, e.g. once the session itself is deleted.
+    pub fn forget_session(&mut self, session_id: &str) {
+        self.sessions.remove(session_id);
+    }
+
+    /// Drops the records of every event sent before `timestamp` and returns
+    /// how many there were. Events that old should be ignored from then on,
+    /// the guard can't catch their replays anymore.
+    pub fn prune_before(&mut self, timestamp: u64) -> usize {
+        let record_count = self.record_count();
+
+        self.sessions.retain(|_, indices| {
+            indices.retain(|_, event| event.timestamp >= timestamp);
+            !indices.is_empty()
+        });
+
+        record_count - self.record_count()
+    }
+
+    /// The number of recorded message indices over all sessions.
+    pub fn record_count(&self) -> usize {
+        self.sessions.values().map(BTreeMap::len).sum()
+    }
+
+    pub fn pickle(&self, pickle_key: &PickleKey) -> Result<String, OlmError> {
+        let plaintext = Zeroizing::new(serde_json::to_vec(&json!({ "version": PICKLE_VERSION, "guard": self }))?);
+
+        let mut nonce = [0u8; NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = cipher(pickle_key)
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| OlmError::Other("The replay guard couldn't be encrypted".to_owned()))?;
+
+        Ok(base64_encode([nonce.as_slice(), &ciphertext].concat()))
+    }
+
+    pub fn from_pickle(pickle: &str, pickle_key: &PickleKey) -> Result<Self, OlmError> {
+        let pickle = base64_decode(pickle)?;
+        if pickle.len() < NONCE_LENGTH {
+            return Err(OlmError::InvalidPickle("The replay guard pickle is too short".to_owned()));
+        }
+
+        let (nonce, ciphertext) = pickle.split_at(NONCE_LENGTH);
+        let plaintext = Zeroizing::new(
+            cipher(pickle_key)
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .map_err(|_| OlmError::InvalidPickle("The replay guard pickle couldn't be decrypted".to_owned()))?,
+        );
+
+        let pickle: ReplayGuardPickle = serde_json::from_slice(&plaintext)?;
+        if pickle.version != PICKLE_VERSION {
+            return Err(OlmError::InvalidPickle(format!("Unknown replay guard pickle version {}", pickle.version)));
+        }
+
+        Ok(pickle.guard)
+    }
+}
+
+/// The pickle key isn't used as is, the guard gets its own subkey.
+fn cipher(pickle_key: &PickleKey) -> ChaCha20Poly1305 {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, pickle_key.as_bytes())
+        .expand(PICKLE_KEY_INFO, key.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+    ChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+}
+
+pub(crate) fn event_metadata(env: &mut JNIEnv, event_id: JString, timestamp: jlong) -> Result<EventMetadata, OlmError> {
+    Ok(EventMetadata { event_id: jstring_to_string(env, event_id)?, timestamp: u64::try_from(timestamp)? })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmReplayGuard__1new(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    jni_call(&mut env, |_| Ok(handles::insert(ReplayGuard::new())))
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmReplayGuard__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        handles::free_handle::<ReplayGuard>(my_ptr);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmReplayGuard__1record(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    session_id: JString,
+    message_index: jlong,
+    event_id: JString,
+    timestamp: jlong,
+) {
+    jni_call(&mut env, |env| {
+        let guard = handles::get::<ReplayGuard>(my_ptr)?;
+        let session_id = jstring_to_string(env, session_id)?;
+        let event = event_metadata(env, event_id, timestamp)?;
+
+        guard.lock().record(&session_id, u32::try_from(message_index)?, event)?;
+
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmReplayGuard__1decrypt<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    session_ptr: jlong,
+    ciphertext: JString<'a>,
+    event_id: JString<'a>,
+    timestamp: jlong,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let guard = handles::get::<ReplayGuard>(my_ptr)?;
+        let session = handles::get::<InboundGroupSession>(session_ptr)?;
+        let ciphertext = jstring_to_string(env, ciphertext)?;
+        let event = event_metadata(env, event_id, timestamp)?;
+
+        let decrypted = guard.lock().decrypt(&mut session.lock(), ciphertext, event)?;
+        let plaintext = env.new_string(String::from_utf8(decrypted.plaintext)?)?;
+
+        Ok(env.new_object(
+            "de/cogia/vodozemac/OlmDecryptedMessage",
+            "(Ljava/lang/String;J)V",
+            &[(&plaintext).into(), JValue::Long(decrypted.message_index as jlong)],
+        )?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmReplayGuard__1decrypt_1bytes<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    session_ptr: jlong,
+    ciphertext: JString<'a>,
+    event_id: JString<'a>,
+    timestamp: jlong,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let guard = handles::get::<ReplayGuard>(my_ptr)?;
+        let session = handles::get::<InboundGroupSession>(session_ptr)?;
+        let ciphertext = jstring_to_string(env, ciphertext)?;
+        let event = event_metadata(env, event_id, timestamp)?;
+
+        let decrypted = guard.lock().decrypt(&mut session.lock(), ciphertext, event)?;
+        let plaintext = env.byte_array_from_slice(&decrypted.plaintext)?;
+
+        Ok(env.new_object(
+            "de/cogia/vodozemac/OlmDecryptedBytes",
+            "([BJ)V",
+            &[(&plaintext).into(), JValue::Long(decrypted.message_index as jlong)],
+        )?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmReplayGuard__1forget_1session(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    session_id: JString,
+) {
+    jni_call(&mut env, |env| {
+        let guard = handles::get::<ReplayGuard>(my_ptr)?;
+        let session_id = jstring_to_string(env, session_id)?;
+        guard.lock().forget_session(&session_id);
+
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmReplayGuard__1prune_1before(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    timestamp: jlong,
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let guard = handles::get::<ReplayGuard>(my_ptr)?;
+        let pruned = guard.lock().prune_before(u64::try_from(timestamp)?);
+
+        Ok(jlong::try_from(pruned)?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmReplayGuard__1record_1count(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let guard = handles::get::<ReplayGuard>(my_ptr)?;
+        let record_count = guard.lock().record_count();
+
+        Ok(jlong::try_from(record_count)?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmReplayGuard__1pickle(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    pickle_key: jlong,
+) -> jstring {
+    jni_call(&mut env, |env| {
+        let guard = handles::get::<ReplayGuard>(my_ptr)?;
+        let pickle_key = handles::get::<PickleKey>(pickle_key)?;
+        let pickle = guard.lock().pickle(&pickle_key.lock())?;
+
+        Ok(env.new_string(pickle)?.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmReplayGuard__1from_1pickle(
+    mut env: JNIEnv,
+    _class: JClass,
+    pickle: JString,
+    pickle_key: jlong,
+) -> jlong {
+    jni_call(&mut env, |env| {
+        let pickle = jstring_to_string(env, pickle)?;
+        let pickle_key = handles::get::<PickleKey>(pickle_key)?;
+        let guard = ReplayGuard::from_pickle(&pickle, &pickle_key.lock())?;
+
+        Ok(handles::insert(guard))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group_sessions::GroupSession;
+    use crate::session_config::MegolmSessionConfig;
+    use crate::test_utils::{key, OTHER_KEY, PICKLE_KEY};
+
+    fn event(event_id: &str, timestamp: u64) -> EventMetadata {
+        EventMetadata { event_id: event_id.to_owned(), timestamp }
+    }
+
+    #[test]
+    fn replayed_ciphertexts_are_rejected() {
+        let mut outbound = GroupSession::new(&MegolmSessionConfig::version_2());
+        let mut session = InboundGroupSession::from_group_session(&outbound);
+        let ciphertext = outbound.encrypt("Hello").to_base64();
+        let mut guard = ReplayGuard::new();
+
+        let decrypted = guard.decrypt(&mut session, ciphertext.clone(), event("$first", 1)).unwrap();
+        assert_eq!(decrypted.plaintext, b"Hello");
+        assert!(guard.decrypt(&mut session, ciphertext.clone(), event("$first", 1)).is_ok());
+
+        for replay in [event("$second", 1), event("$first", 2)] {
+            assert!(matches!(
+                guard.decrypt(&mut session, ciphertext.clone(), replay),
+                Err(OlmError::ReplayedMessage { message_index: 0, ref previous_event_id, .. })
+                    if previous_event_id == "$first"
+            ));
+        }
+    }
+
+    #[test]
+    fn failed_decryptions_are_not_recorded() {
+        let mut outbound = GroupSession::new(&MegolmSessionConfig::version_2());
+        let mut session = InboundGroupSession::from_group_session(&outbound);
+        let ciphertext = outbound.encrypt("Hello").to_base64();
+        let mut guard = ReplayGuard::new();
+
+        let mut other = InboundGroupSession::from_group_session(&GroupSession::new(&MegolmSessionConfig::version_2()));
+        assert!(guard.decrypt(&mut other, ciphertext.clone(), event("$forged", 1)).is_err());
+
+        assert!(guard.decrypt(&mut session, ciphertext, event("$event", 1)).is_ok());
+    }
+
+    #[test]
+    fn indices_are_kept_per_session() {
+        let mut guard = ReplayGuard::new();
+
+        guard.record("session", 0, event("$first", 1)).unwrap();
+        guard.record("other session", 0, event("$second", 2)).unwrap();
+        assert!(guard.record("session", 0, event("$second", 2)).is_err());
+
+        guard.forget_session("session");
+        assert!(guard.record("session", 0, event("$second", 2)).is_ok());
+    }
+
+    #[test]
+    fn old_records_are_pruned() {
+        let mut guard = ReplayGuard::new();
+        guard.record("session", 0, event("$old", 1)).unwrap();
+        guard.record("session", 1, event("$new", 5)).unwrap();
+        guard.record("other session", 0, event("$older", 0)).unwrap();
+        assert_eq!(guard.record_count(), 3);
+
+        assert_eq!(guard.prune_before(5), 2);
+        assert_eq!(guard.record_count(), 1);
+        assert!(!guard.sessions.contains_key("other session"));
+
+        assert!(guard.record("session", 0, event("$replay", 6)).is_ok());
+        assert!(guard.record("session", 1, event("$replay", 6)).is_err());
+    }
+
+    #[test]
+    fn pickle_round_trip() {
+        let mut guard = ReplayGuard::new();
+        guard.record("session", 3, event("$event", 1)).unwrap();
+
+        let pickle = guard.pickle(&key(PICKLE_KEY)).unwrap();
+        let mut unpickled = ReplayGuard::from_pickle(&pickle, &key(PICKLE_KEY)).unwrap();
+
+        assert!(unpickled.record("session", 3, event("$event", 1)).is_ok());
+        assert!(unpickled.record("session", 3, event("$other", 1)).is_err());
+        assert!(matches!(ReplayGuard::from_pickle(&pickle, &key(OTHER_KEY)), Err(OlmError::InvalidPickle(_))));
+        assert!(matches!(ReplayGuard::from_pickle("AAAA", &key(PICKLE_KEY)), Err(OlmError::InvalidPickle(_))));
+    }
+}
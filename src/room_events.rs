@@ -0,0 +1,305 @@
+//! Encryption of whole room events with Megolm.
+//!
+//! The plaintext of an `m.room.encrypted` event is the JSON object
+//! `{type, content, room_id}`. The room id is part of it so that a server
+//! can't move an encrypted event into another room, `decrypt_room_event`
+//! checks it.
+
+use jni::JNIEnv;
+use jni::objects::{JClass, JObject, JString, JValue};
+use jni::sys::{jlong, jstring};
+use serde_json::{json, Value};
+
+use super::{
+    canonical_json::{self, JsonObject},
+    errors::OlmError,
+    group_sessions::{GroupSession, InboundGroupSession},
+    handles, jni_call, jstring_to_string,
+    replay_guard::{event_metadata, EventMetadata, ReplayGuard},
+};
+
+const MEGOLM_ALGORITHM: &str = "m.megolm.v1.aes-sha2";
+
+/// A decrypted `m.room.encrypted` event.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecryptedRoomEvent {
+    pub event_type: String,
+    /// The JSON object of the event content.
+    pub content: String,
+    pub message_index: u32,
+}
+
+/// Encrypts an event of `event_type` with the JSON object `content` for
+/// `room_id`, and returns the content of the `m.room.encrypted` event to send.
+pub fn encrypt_room_event(
+    session: &mut GroupSession,
+    room_id: &str,
+    event_type: &str,
+    content: &str,
+    sender_key: &str,
+    device_id: &str,
+) -> Result<String, OlmError> {
+    let content = canonical_json::parse_object(content)?;
+    let payload = json!({ "type": event_type, "content": content, "room_id": room_id });
+
+    // Only signed JSON has to be canonical, the content may contain floats
+    // and large integers that canonical JSON forbids.
+    let message = session.encrypt(serde_json::to_string(&payload)?);
+
+    Ok(json!({
+        "algorithm": MEGOLM_ALGORITHM,
+        "ciphertext": message.to_base64(),
+        "sender_key": sender_key,
+        "device_id": device_id,
+        "session_id": session.session_id(),
+    })
+    .to_string())
+}
+
+/// Decrypts the content of an `m.room.encrypted` event received in `room_id`.
+///
+/// With a replay guard the message index is recorded for the given event,
+/// see `ReplayGuard::record`. Only an event that passes every check is
+/// recorded, a forged one mustn't claim the index of the real event.
+pub fn decrypt_room_event(
+    session: &mut InboundGroupSession,
+    room_id: &str,
+    encrypted_content: &str,
+    replay_guard: Option<(&mut ReplayGuard, EventMetadata)>,
+) -> Result<DecryptedRoomEvent, OlmError> {
+    let encrypted_content = canonical_json::parse_object(encrypted_content)?;
+
+    let algorithm = string_field(&encrypted_content, "algorithm")?;
+    if algorithm != MEGOLM_ALGORITHM {
+        return Err(OlmError::InvalidEvent(format!("Unsupported algorithm {algorithm}")));
+    }
+    let session_id = string_field(&encrypted_content, "session_id")?;
+    if session_id != session.session_id() {
+        return Err(OlmError::InvalidEvent(format!(
+            "The event was encrypted with session {session_id}, not {}",
+            session.session_id()
+        )));
+    }
+    let ciphertext = string_field(&encrypted_content, "ciphertext")?.to_owned();
+
+    let decrypted = session.decrypt_bytes(ciphertext)?;
+
+    let payload: JsonObject = serde_json::from_slice(&decrypted.plaintext).map_err(|error| {
+        OlmError::InvalidEvent(format!("The decrypted payload isn't a JSON object: {error}"))
+    })?;
+
+    let event_room_id = payload.get("room_id").and_then(Value::as_str);
+    if event_room_id != Some(room_id) {
+        return Err(OlmError::RoomIdMismatch {
+            expected: room_id.to_owned(),
+            actual: event_room_id.map(str::to_owned),
+        });
+    }
+
+    let content = match payload.get("content") {
+        Some(content @ Value::Object(_)) => content.to_string(),
+        _ => return Err(OlmError::InvalidEvent("The decrypted payload has no content object".to_owned())),
+    };
+
+    let event_type = string_field(&payload, "type")?.to_owned();
+
+    if let Some((guard, event)) = replay_guard {
+        guard.record(&session.session_id(), decrypted.message_index, event)?;
+    }
+
+    Ok(DecryptedRoomEvent { event_type, content, message_index: decrypted.message_index })
+}
+
+fn string_field<'a>(object: &'a JsonObject, field: &str) -> Result<&'a str, OlmError> {
+    object
+        .get(field)
+        .and_then(Value::as_str)
+        .ok_or_else(|| OlmError::InvalidEvent(format!("The event has no {field} string")))
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmGroupSession__1encrypt_1room_1event(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    room_id: JString,
+    event_type: JString,
+    content: JString,
+    sender_key: JString,
+    device_id: JString,
+) -> jstring {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<GroupSession>(my_ptr)?;
+        let room_id = jstring_to_string(env, room_id)?;
+        let event_type = jstring_to_string(env, event_type)?;
+        let content = jstring_to_string(env, content)?;
+        let sender_key = jstring_to_string(env, sender_key)?;
+        let device_id = jstring_to_string(env, device_id)?;
+
+        let encrypted = encrypt_room_event(
+            &mut session.lock(),
+            &room_id,
+            &event_type,
+            &content,
+            &sender_key,
+            &device_id,
+        )?;
+
+        Ok(env.new_string(encrypted)?.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmInboundGroupSession__1decrypt_1room_1event<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    room_id: JString<'a>,
+    encrypted_content: JString<'a>,
+    guard_ptr: jlong,
+    event_id: JString<'a>,
+    timestamp: jlong,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<InboundGroupSession>(my_ptr)?;
+        let room_id = jstring_to_string(env, room_id)?;
+        let encrypted_content = jstring_to_string(env, encrypted_content)?;
+
+        // Zero is never a valid handle, Java passes it without a guard. The
+        // guard is locked before the session, like in `replay_guard`.
+        let guard = match guard_ptr {
+            0 => None,
+            ptr => Some(handles::get::<ReplayGuard>(ptr)?),
+        };
+        let mut guard = guard.as_ref().map(|guard| guard.lock());
+        let replay_guard = match guard.as_deref_mut() {
+            Some(guard) => Some((guard, event_metadata(env, event_id, timestamp)?)),
+            None => None,
+        };
+
+        let event = decrypt_room_event(&mut session.lock(), &room_id, &encrypted_content, replay_guard)?;
+
+        let event_type = env.new_string(event.event_type)?;
+        let content = env.new_string(event.content)?;
+
+        Ok(env.new_object(
+            "de/cogia/vodozemac/DecryptedRoomEvent",
+            "(Ljava/lang/String;Ljava/lang/String;J)V",
+            &[(&event_type).into(), (&content).into(), JValue::Long(event.message_index as jlong)],
+        )?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_config::MegolmSessionConfig;
+
+    const ROOM_ID: &str = "!room:example.org";
+
+    fn sessions() -> (GroupSession, InboundGroupSession) {
+        let outbound = GroupSession::new(&MegolmSessionConfig::version_2());
+        let inbound = InboundGroupSession::from_group_session(&outbound);
+
+        (outbound, inbound)
+    }
+
+    fn encrypt(outbound: &mut GroupSession, room_id: &str) -> String {
+        let content = r#"{"msgtype":"m.text","body":"Hello"}"#;
+
+        encrypt_room_event(outbound, room_id, "m.room.message", content, "curve", "DEVICE").unwrap()
+    }
+
+    #[test]
+    fn room_event_round_trip() {
+        let (mut outbound, mut inbound) = sessions();
+
+        let encrypted = encrypt(&mut outbound, ROOM_ID);
+        let envelope = canonical_json::parse_object(&encrypted).unwrap();
+        assert_eq!(envelope["algorithm"], MEGOLM_ALGORITHM);
+        assert_eq!(envelope["session_id"], outbound.session_id());
+        assert_eq!(envelope["sender_key"], "curve");
+        assert_eq!(envelope["device_id"], "DEVICE");
+
+        let event = decrypt_room_event(&mut inbound, ROOM_ID, &encrypted, None).unwrap();
+        assert_eq!(event.event_type, "m.room.message");
+        assert_eq!(event.content, r#"{"body":"Hello","msgtype":"m.text"}"#);
+        assert_eq!(event.message_index, 0);
+    }
+
+    #[test]
+    fn content_with_floats_and_large_integers_round_trips() {
+        let (mut outbound, mut inbound) = sessions();
+        let content = r#"{"body":"Hello","duration":1.5,"size":9007199254740993}"#;
+
+        let encrypted =
+            encrypt_room_event(&mut outbound, ROOM_ID, "m.room.message", content, "curve", "DEVICE").unwrap();
+
+        let event = decrypt_room_event(&mut inbound, ROOM_ID, &encrypted, None).unwrap();
+        assert_eq!(event.content, content);
+    }
+
+    #[test]
+    fn events_from_another_room_are_rejected() {
+        let (mut outbound, mut inbound) = sessions();
+
+        let encrypted = encrypt(&mut outbound, "!other:example.org");
+
+        assert!(matches!(
+            decrypt_room_event(&mut inbound, ROOM_ID, &encrypted, None),
+            Err(OlmError::RoomIdMismatch { actual: Some(ref actual), .. }) if actual == "!other:example.org"
+        ));
+    }
+
+    #[test]
+    fn malformed_envelopes_are_rejected() {
+        let (mut outbound, mut inbound) = sessions();
+        let encrypted = canonical_json::parse_object(&encrypt(&mut outbound, ROOM_ID)).unwrap();
+
+        for (field, value) in [
+            ("algorithm", json!("m.olm.v1.curve25519-aes-sha2")),
+            ("session_id", json!("another session")),
+            ("ciphertext", json!(1)),
+        ] {
+            let mut envelope = encrypted.clone();
+            envelope.insert(field.to_owned(), value);
+
+            assert!(matches!(
+                decrypt_room_event(&mut inbound, ROOM_ID, &Value::Object(envelope).to_string(), None),
+                Err(OlmError::InvalidEvent(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn replays_are_rejected_with_a_guard() {
+        let (mut outbound, mut inbound) = sessions();
+        let encrypted = encrypt(&mut outbound, ROOM_ID);
+        let mut guard = ReplayGuard::new();
+        let event = |event_id: &str| EventMetadata { event_id: event_id.to_owned(), timestamp: 1 };
+
+        assert!(decrypt_room_event(&mut inbound, ROOM_ID, &encrypted, Some((&mut guard, event("$first")))).is_ok());
+        assert!(matches!(
+            decrypt_room_event(&mut inbound, ROOM_ID, &encrypted, Some((&mut guard, event("$second")))),
+            Err(OlmError::ReplayedMessage { .. })
+        ));
+    }
+
+    #[test]
+    fn rejected_events_dont_claim_the_message_index() {
+        let (mut outbound, mut inbound) = sessions();
+        let other_room = "!other:example.org";
+        let encrypted = encrypt(&mut outbound, other_room);
+        let mut guard = ReplayGuard::new();
+        let event = |event_id: &str| EventMetadata { event_id: event_id.to_owned(), timestamp: 1 };
+
+        assert!(matches!(
+            decrypt_room_event(&mut inbound, ROOM_ID, &encrypted, Some((&mut guard, event("$moved")))),
+            Err(OlmError::RoomIdMismatch { .. })
+        ));
+
+        let decrypted =
+            decrypt_room_event(&mut inbound, other_room, &encrypted, Some((&mut guard, event("$genuine")))).unwrap();
+        assert_eq!(decrypted.message_index, 0);
+    }
+}
@@ -1,22 +1,8 @@
-use std::error::Error;
 use jni::JNIEnv;
 use jni::objects::{JClass, JLongArray, JString};
 use jni::sys::{jboolean, jlong, jstring};
-use crate::{jstring_to_string, result_or_java_exception, CustomError};
+use crate::{curve25519::Curve25519PublicKey, errors::OlmError, handles, jni_call, jstring_to_string};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Curve25519PublicKey(pub(crate) vodozemac::Curve25519PublicKey);
-
-impl Curve25519PublicKey {
-    pub fn from_base64(key: &str) -> Result<Box<Curve25519PublicKey>, Box<dyn Error>> {
-        Ok(Curve25519PublicKey(vodozemac::Curve25519PublicKey::from_base64(key).map_err(|err: _| Box::new(err) as Box<dyn Error>)?).into())
-    }
-
-    #[allow(clippy::wrong_self_convention)]
-    pub fn to_base64(&self) -> String {
-        self.0.to_base64()
-    }
-}
 pub struct Sas {
     inner: Option<vodozemac::sas::Sas>,
 }
@@ -36,14 +22,15 @@ impl Sas {
         return String::new();
     }
 
-    pub fn diffie_hellman(&mut self, key: String) -> Result<EstablishedSas, Box<dyn Error>> {
+    pub fn diffie_hellman(&mut self, key: String) -> Result<EstablishedSas, OlmError> {
         if let Some(sas) = self.inner.take() {
-            let pub_key = Curve25519PublicKey::from_base64(&key).unwrap();
-            let sass = sas.diffie_hellman(pub_key.0)
-                .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+            let pub_key = Curve25519PublicKey::from_base64(&key)?;
+            let sass = sas
+                .diffie_hellman(pub_key.inner)
+                .map_err(OlmError::key("curve25519"))?;
             Ok(EstablishedSas { inner: sass })
         } else {
-            Err(Box::new(CustomError("Invalid message type, expected a pre-key message".to_owned())))
+            Err(OlmError::Other("The SAS object was already used".to_owned()))
         }
     }
 }
@@ -66,13 +53,11 @@ impl EstablishedSas {
         self.inner.calculate_mac_invalid_base64(&input, &info)
     }
 
-    pub fn verify_mac(&self, input: String, info: String, tag: String) -> Result<(), Box<dyn Error>> {
-        let tag = vodozemac::sas::Mac::from_base64(&tag)
-            .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+    pub fn verify_mac(&self, input: String, info: String, tag: String) -> Result<(), OlmError> {
+        let tag = vodozemac::sas::Mac::from_base64(&tag)?;
 
         self.inner
-            .verify_mac(&input, &info, &tag)
-            .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+            .verify_mac(&input, &info, &tag)?;
 
         Ok(())
     }
@@ -96,8 +81,49 @@ impl SasBytes {
 
 
 #[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_OlmSas__1new() -> jlong {
-    Box::into_raw(Box::new(Sas::new())) as jlong
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSas__1new(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    jni_call(&mut env, |_| Ok(handles::insert(Sas::new())))
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSas__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        // Once the last reference is gone, vodozemac's `ZeroizeOnDrop` impls wipe the key material.
+        handles::free_handle::<Sas>(my_ptr);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmEstablishedSas__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        // Once the last reference is gone, vodozemac's `ZeroizeOnDrop` impls wipe the shared secret.
+        handles::free_handle::<EstablishedSas>(my_ptr);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSasBytes__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        handles::free_handle::<SasBytes>(my_ptr);
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -106,14 +132,12 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmSas__1public_1key(
     _class: JClass,
     my_ptr: jlong,
 ) -> jstring {
-    let sas = unsafe { &mut *(my_ptr as *mut Sas) };
+    jni_call(&mut env, |env| {
+        let sas = handles::get::<Sas>(my_ptr)?;
+        let public_key = sas.lock().public_key();
 
-    // Convert the output Rust String to a new jstring and return it
-    let output_jstring: jstring = **env
-        .new_string(sas.public_key())
-        .expect("Failed to create output ed25519_key");
-
-    output_jstring
+        Ok(env.new_string(public_key)?.into_raw())
+    })
 }
 
 #[no_mangle]
@@ -123,19 +147,13 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmSas__1diffie_1hellman(
     my_ptr: jlong,
     key: JString,
 ) -> jlong {
-    let sas = unsafe { &mut *(my_ptr as *mut Sas) };
-    let localKey = jstring_to_string(&mut env, key);
+    jni_call(&mut env, |env| {
+        let sas = handles::get::<Sas>(my_ptr)?;
+        let local_key = jstring_to_string(env, key)?;
+        let established = sas.lock().diffie_hellman(local_key)?;
 
-    let res;
-    match result_or_java_exception(&mut env, sas.diffie_hellman(localKey)) {
-        Ok(value) => {
-            res =  Box::into_raw(Box::new(value)) as jlong
-        }
-        Err(_) => {
-            res = -1;
-        }
-    }
-    res
+        Ok(handles::insert(established))
+    })
 }
 
 #[no_mangle]
@@ -145,10 +163,13 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmEstablishedSas__1bytes(
     my_ptr: jlong,
     info: JString,
 ) -> jlong {
-    let sas = unsafe { &mut *(my_ptr as *mut EstablishedSas) };
-    let localKey = jstring_to_string(&mut env, info);
-    let established = sas.bytes(localKey);
-    Box::into_raw(Box::new(established)) as jlong
+    jni_call(&mut env, |env| {
+        let sas = handles::get::<EstablishedSas>(my_ptr)?;
+        let local_info = jstring_to_string(env, info)?;
+        let bytes = sas.lock().bytes(local_info);
+
+        Ok(handles::insert(bytes))
+    })
 }
 
 #[no_mangle]
@@ -159,37 +180,34 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmEstablishedSas__1calculate_1mac(
     input: JString,
     info: JString,
 ) -> jstring {
-    let sas = unsafe { &mut *(my_ptr as *mut EstablishedSas) };
-    let local_input = jstring_to_string(&mut env, input);
-    let local_key = jstring_to_string(&mut env, info);
-
-    let output_jstring: jstring = **env
-        .new_string(sas.calculate_mac(local_input, local_key))
-        .expect("Failed to create output ed25519_key");
-
-    output_jstring
+    jni_call(&mut env, |env| {
+        let sas = handles::get::<EstablishedSas>(my_ptr)?;
+        let local_input = jstring_to_string(env, input)?;
+        let local_info = jstring_to_string(env, info)?;
+        let mac = sas.lock().calculate_mac(local_input, local_info);
+
+        Ok(env.new_string(mac)?.into_raw())
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_OlmEstablishedSas__1calculate_1mac_1invalid_1sbase64(
+pub extern "C" fn Java_de_cogia_vodozemac_OlmEstablishedSas__1calculate_1mac_1invalid_1base64(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
     input: JString,
     info: JString,
 ) -> jstring {
-    let sas = unsafe { &mut *(my_ptr as *mut EstablishedSas) };
-    let local_input = jstring_to_string(&mut env, input);
-    let local_key = jstring_to_string(&mut env, info);
-
-    let output_jstring: jstring = **env
-        .new_string(sas.calculate_mac_invalid_base64(local_input, local_key))
-        .expect("Failed to create output ed25519_key");
-
-    output_jstring
+    jni_call(&mut env, |env| {
+        let sas = handles::get::<EstablishedSas>(my_ptr)?;
+        let local_input = jstring_to_string(env, input)?;
+        let local_info = jstring_to_string(env, info)?;
+        let mac = sas.lock().calculate_mac_invalid_base64(local_input, local_info);
+
+        Ok(env.new_string(mac)?.into_raw())
+    })
 }
 
-
 #[no_mangle]
 pub extern "C" fn Java_de_cogia_vodozemac_OlmEstablishedSas__1verify_1mac(
     mut env: JNIEnv,
@@ -199,50 +217,46 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmEstablishedSas__1verify_1mac(
     info: JString,
     tag: JString
 ) -> jboolean {
-    let sas = unsafe { &mut *(my_ptr as *mut EstablishedSas) };
-    let local_input = jstring_to_string(&mut env, input);
-    let local_key = jstring_to_string(&mut env, info);
-    let local_tag = jstring_to_string(&mut env, tag);
+    jni_call(&mut env, |env| {
+        let sas = handles::get::<EstablishedSas>(my_ptr)?;
+        let local_input = jstring_to_string(env, input)?;
+        let local_info = jstring_to_string(env, info)?;
+        let local_tag = jstring_to_string(env, tag)?;
 
+        sas.lock().verify_mac(local_input, local_info, local_tag)?;
 
-    let res;
-    match result_or_java_exception(&mut env, sas.verify_mac(local_input, local_key, local_tag)) {
-        Ok(value) => {
-            res =  true
-        }
-        Err(_) => {
-            res = false;
-        }
-    }
-
-    res
+        Ok(true)
+    })
 }
 
-
-
 #[no_mangle]
 pub extern "C" fn Java_de_cogia_vodozemac_OlmSasBytes__1decimals<'local>(
     mut env: JNIEnv<'local>,
     _class: JClass,
     my_ptr: jlong,
 ) -> JLongArray<'local> {
-    let sas = unsafe { &mut *(my_ptr as *mut SasBytes) };
-    let long_vec: Vec<jlong> =  sas.decimals().iter().map(|&x| x as jlong).collect();
-    let long_array = env.new_long_array(long_vec.len() as i32).unwrap();
-    env.set_long_array_region(&long_array, 0, &long_vec).unwrap();
-    long_array
+    jni_call(&mut env, |env| {
+        let sas = handles::get::<SasBytes>(my_ptr)?;
+        let long_vec: Vec<jlong> = sas.lock().decimals().iter().map(|&x| x as jlong).collect();
+        let long_array = env.new_long_array(long_vec.len() as i32)?;
+        env.set_long_array_region(&long_array, 0, &long_vec)?;
+
+        Ok(long_array)
+    })
 }
 
-
 #[no_mangle]
 pub extern "C" fn Java_de_cogia_vodozemac_OlmSasBytes__1emoji_1indices<'local>(
     mut env: JNIEnv<'local>,
     _class: JClass,
     my_ptr: jlong,
 ) -> JLongArray<'local> {
-    let sas = unsafe { &mut *(my_ptr as *mut SasBytes) };
-    let long_vec: Vec<jlong> =  sas.emoji_indices().iter().map(|&x| x as jlong).collect();
-    let long_array = env.new_long_array(long_vec.len() as i32).unwrap();
-    env.set_long_array_region(&long_array, 0, &long_vec).unwrap();
-    long_array
+    jni_call(&mut env, |env| {
+        let sas = handles::get::<SasBytes>(my_ptr)?;
+        let long_vec: Vec<jlong> = sas.lock().emoji_indices().iter().map(|&x| x as jlong).collect();
+        let long_array = env.new_long_array(long_vec.len() as i32)?;
+        env.set_long_array_region(&long_array, 0, &long_vec)?;
+
+        Ok(long_array)
+    })
 }
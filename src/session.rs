@@ -1,40 +1,29 @@
-use std::error::Error;
 use jni::JNIEnv;
-use jni::objects::{JClass, JObject, JString, JValue};
-use jni::sys::{jboolean, jlong, jstring};
-use vodozemac::{base64_decode, base64_encode};
-use super::{jstring_to_string, result_or_java_exception, CustomError, OlmMessage};
+use jni::objects::{JByteArray, JByteBuffer, JClass, JObject, JString};
+use jni::sys::{jboolean, jbyteArray, jint, jlong, jstring};
+use super::{advance_buffer, curve25519::Curve25519PublicKey, direct_buffer, errors::OlmError, handles, jbytes_to_secret, jni_call, jstring_to_string, pickle_key::PickleKey, olm_message::OlmMessage, session_config::OlmSessionConfig};
 
 pub struct Session {
     pub(super) inner: vodozemac::olm::Session,
 }
 
 impl Session {
-    pub fn pickle(&self, pickle_key: String) -> Result<String, Box<dyn Error>> {
-        let pickle_key: &[u8; 32] = pickle_key
-            .as_bytes()
-            .try_into()
-            .map_err(|_| Box::new(CustomError("Invalid pickle key length, expected 32 bytes".to_owned())))?;
-
-        Ok(self.inner.pickle().encrypt(pickle_key))
+    pub fn pickle(&self, pickle_key: &PickleKey) -> String {
+        self.inner.pickle().encrypt(pickle_key.as_bytes())
     }
 
-    pub fn from_pickle(pickle: String, pickle_key: String) -> Result<Session, Box<dyn Error>> {
-        let pickle_key: &[u8; 32] = pickle_key
-            .as_bytes()
-            .try_into()
-            .map_err(|_| Box::new(CustomError("Invalid pickle key length, expected 32 bytes".to_owned())))?;
-        let pickle = vodozemac::olm::SessionPickle::from_encrypted(&pickle, pickle_key)
-            .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+    pub fn from_pickle(pickle: String, pickle_key: &PickleKey) -> Result<Session, OlmError> {
+        let pickle = vodozemac::olm::SessionPickle::from_encrypted(&pickle, pickle_key.as_bytes())?;
 
         let session = vodozemac::olm::Session::from_pickle(pickle);
 
         Ok(Self { inner: session })
     }
 
-    pub fn from_libolm_pickle(pickle: String, pickle_key: String) -> Result<Session, Box<dyn Error>> {
+    /// Import only, vodozemac can't write libolm session pickles.
+    pub fn from_libolm_pickle(pickle: String, pickle_key: &[u8]) -> Result<Session, OlmError> {
         let session =
-            vodozemac::olm::Session::from_libolm_pickle(&pickle, &pickle_key.as_bytes()).map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+            vodozemac::olm::Session::from_libolm_pickle(&pickle, pickle_key)?;
 
         Ok(Self { inner: session })
     }
@@ -43,51 +32,73 @@ impl Session {
         self.inner.session_id()
     }
 
-    pub fn session_matches(&self, message: &OlmMessage) -> bool {
-        let message =
-            vodozemac::olm::OlmMessage::from_parts(
-                message.message_type.try_into().unwrap(),
-                &base64_decode(&message.ciphertext).unwrap_or_else(|_| Vec::new())
-            );
-
-        match message {
-            Ok(m) => {
-                if let vodozemac::olm::OlmMessage::PreKey(m) = m {
-                    self.inner.session_keys() == m.session_keys()
-                } else {
-                    false
-                }
-            }
-            Err(_) => false,
-        }
+    pub fn session_config(&self) -> OlmSessionConfig {
+        self.inner.session_config().into()
     }
 
-    pub fn encrypt(&mut self, plaintext: String) -> OlmMessage {
-        let message = self.inner.encrypt(plaintext);
+    /// Whether a message from the other side was decrypted yet, until then
+    /// `encrypt` keeps producing pre-key messages.
+    pub fn has_received_message(&self) -> bool {
+        self.inner.has_received_message()
+    }
 
-        let (message_type, ciphertext) = message.to_parts();
+    /// The keys the session was established with.
+    pub fn session_keys(&self) -> vodozemac::olm::SessionKeys {
+        self.inner.session_keys()
+    }
 
-        OlmMessage {
-            ciphertext: base64_encode(ciphertext), //String::from_utf8_lossy(&ciphertext).into_owned(),
-            message_type: message_type.try_into().unwrap(),
-        }
+    /// A summary of the ratchet state for logs: chain indices, ratchet counts
+    /// and public ratchet keys, but no secrets.
+    pub fn describe(&self) -> String {
+        format!("{:?}", self.inner)
     }
 
-    pub fn decrypt(&mut self, message: &OlmMessage) -> Result<String, Box<dyn Error>> {
-        let _message = vodozemac::olm::OlmMessage::from_parts(
-            message.message_type.try_into().unwrap(),
-            &base64_decode(&message.ciphertext).map_err(|err| Box::new(err) as Box<dyn Error>)?,
-        )
-            .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+    pub fn session_matches(&self, message: &OlmMessage) -> bool {
+        message.session_keys() == Some(self.inner.session_keys())
+    }
 
-        let decrypted_message = self.inner.decrypt(&_message)
-            .map_err(|err: _| Box::new(err) as Box<dyn Error>)?;
+    pub fn encrypt(&mut self, plaintext: impl AsRef<[u8]>) -> OlmMessage {
+        self.inner.encrypt(plaintext).into()
+    }
 
-        let decrypted_message = String::from_utf8(decrypted_message)
-            .map_err(|err| Box::new(err) as Box<dyn Error>)?;
+    pub fn decrypt(&mut self, message: &OlmMessage) -> Result<String, OlmError> {
+        let decrypted_message = String::from_utf8(self.decrypt_bytes(message)?)?;
 
         Ok(decrypted_message)
     }
+
+    pub fn decrypt_bytes(&mut self, message: &OlmMessage) -> Result<Vec<u8>, OlmError> {
+        Ok(self.inner.decrypt(&message.inner)?)
+    }
+
+    /// Decrypts into `output` and returns the length of the plaintext.
+    ///
+    /// A successful decryption uses up the message key, so `output` is checked
+    /// first: it has to fit the longest plaintext the message can hold.
+    pub fn decrypt_into(&mut self, message: &OlmMessage, output: &mut [u8]) -> Result<usize, OlmError> {
+        let max_length = message.max_plaintext_length();
+        if output.len() < max_length {
+            return Err(OlmError::InvalidArgument(format!("The plaintext can need up to {max_length} bytes")));
+        }
+
+        let plaintext = self.decrypt_bytes(message)?;
+        output[..plaintext.len()].copy_from_slice(&plaintext);
+
+        Ok(plaintext.len())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        // Once the last reference is gone, vodozemac's `ZeroizeOnDrop` impls wipe the key material.
+        handles::free_handle::<Session>(my_ptr);
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -95,24 +106,15 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1pickle(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
-    pickle_key: JString
+    pickle_key: jlong
 ) -> jstring {
-    let session = unsafe { &mut *(my_ptr as *mut Session) };
-    let p_key: String = env.get_string(&pickle_key).expect("Couldn't get Java string").into();
-
-    let pickle;
-    match result_or_java_exception(&mut env, session.pickle(p_key)) {
-        Ok(value) => {
-            pickle = **env
-                .new_string(value)
-                .expect("Failed to create output session_id");;
-        }
-        Err(_) => {
-            pickle = std::ptr::null_mut()
-        }
-    }
+    jni_call(&mut env, |env| {
+        let session = handles::get::<Session>(my_ptr)?;
+        let pickle_key = handles::get::<PickleKey>(pickle_key)?;
+        let pickle = session.lock().pickle(&pickle_key.lock());
 
-    pickle
+        Ok(env.new_string(pickle)?.into_raw())
+    })
 }
 
 #[no_mangle]
@@ -120,15 +122,16 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1from_1pickle(
     mut env: JNIEnv,
     _class: JClass,
     pickle: JString,
-    pickle_key: JString
+    pickle_key: jlong
 ) -> jlong {
-
-    let acc = Session::from_pickle(
-        jstring_to_string(&mut env, pickle),
-        jstring_to_string(&mut env, pickle_key)
-    );
-
-    Box::into_raw(Box::new(acc)) as jlong
+    jni_call(&mut env, |env| {
+        let session = Session::from_pickle(
+            jstring_to_string(env, pickle)?,
+            &handles::get::<PickleKey>(pickle_key)?.lock()
+        )?;
+
+        Ok(handles::insert(session))
+    })
 }
 
 #[no_mangle]
@@ -136,51 +139,119 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1from_1pickle_1lib_1olm(
     mut env: JNIEnv,
     _class: JClass,
     pickle: JString,
-    pickle_key: JString
+    pickle_key: JByteArray
 ) -> jlong {
+    jni_call(&mut env, |env| {
+        let session = Session::from_libolm_pickle(
+            jstring_to_string(env, pickle)?,
+            &jbytes_to_secret(env, pickle_key)?
+        )?;
+
+        Ok(handles::insert(session))
+    })
+}
 
-    let acc = Session::from_libolm_pickle(
-        jstring_to_string(&mut env, pickle),
-        jstring_to_string(&mut env, pickle_key)
-    );
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1session_1id(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jstring {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<Session>(my_ptr)?;
+        let session_id = session.lock().session_id();
 
-    Box::into_raw(Box::new(acc)) as jlong
+        Ok(env.new_string(session_id)?.into_raw())
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1session_1id(
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1session_1config(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let session = handles::get::<Session>(my_ptr)?;
+        let config = session.lock().session_config();
+
+        Ok(handles::insert(config))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1has_1received_1message(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jboolean {
+    jni_call(&mut env, |_| {
+        let session = handles::get::<Session>(my_ptr)?;
+        let received = session.lock().has_received_message();
+
+        Ok(received)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1session_1keys<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<Session>(my_ptr)?;
+        let keys = session.lock().session_keys();
+
+        session_keys_object(env, keys)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1describe(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
 ) -> jstring {
-    let session = unsafe { &mut *(my_ptr as *mut Session) };
+    jni_call(&mut env, |env| {
+        let session = handles::get::<Session>(my_ptr)?;
+        let description = session.lock().describe();
 
-    // Convert the output Rust String to a new jstring and return it
-    let output_jstring: jstring = **env
-        .new_string(session.session_id())
-        .expect("Failed to create output session_id");
+        Ok(env.new_string(description)?.into_raw())
+    })
+}
 
-    output_jstring
+/// Builds a `de.cogia.vodozemac.OlmSessionKeys`.
+pub fn session_keys_object<'a>(
+    env: &mut JNIEnv<'a>,
+    keys: vodozemac::olm::SessionKeys,
+) -> Result<JObject<'a>, OlmError> {
+    let identity_key = Curve25519PublicKey::from(keys.identity_key).to_java(env)?;
+    let base_key = Curve25519PublicKey::from(keys.base_key).to_java(env)?;
+    let one_time_key = Curve25519PublicKey::from(keys.one_time_key).to_java(env)?;
+
+    Ok(env.new_object(
+        "de/cogia/vodozemac/OlmSessionKeys",
+        "(Lde/cogia/vodozemac/Curve25519PublicKey;Lde/cogia/vodozemac/Curve25519PublicKey;Lde/cogia/vodozemac/Curve25519PublicKey;)V",
+        &[(&identity_key).into(), (&base_key).into(), (&one_time_key).into()],
+    )?)
 }
 
-// session_matches
 #[no_mangle]
 pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1session_1matches(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
-    ciphertext: JString,
-    message_type: jlong,
+    message_ptr: jlong,
 ) -> jboolean {
-    let session = unsafe { &mut *(my_ptr as *mut Session) };
+    jni_call(&mut env, |_| {
+        let session = handles::get::<Session>(my_ptr)?;
 
-    let message = OlmMessage {
-        ciphertext: jstring_to_string(&mut env, ciphertext),
-        message_type: message_type.try_into().unwrap(),
-    };
-    let res = session.session_matches(&message);
+        let message = handles::get::<OlmMessage>(message_ptr)?;
+        let matches = session.lock().session_matches(&message.lock());
 
-    res
+        Ok(matches)
+    })
 }
 
 #[no_mangle]
@@ -188,29 +259,56 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1decrypt(
     mut env: JNIEnv,
     _class: JClass,
     my_ptr: jlong,
-    ciphertext: JString,
-    message_type: jlong,
+    message_ptr: jlong,
 ) -> jstring {
-    let session = unsafe { &mut *(my_ptr as *mut Session) };
-
-    let message = OlmMessage {
-        ciphertext: jstring_to_string(&mut env, ciphertext),
-        message_type: message_type.try_into().unwrap(),
-    };
-
-    let res;
-    match result_or_java_exception(&mut env, session.decrypt(&message)) {
-        Ok(value) => {
-            res = **env
-                .new_string(value)
-                .expect("Failed to create output session_id");;
-        }
-        Err(_) => {
-            res = std::ptr::null_mut()
-        }
-    }
+    jni_call(&mut env, |env| {
+        let session = handles::get::<Session>(my_ptr)?;
 
-    res
+        let message = handles::get::<OlmMessage>(message_ptr)?;
+        let plaintext = session.lock().decrypt(&message.lock())?;
+
+        Ok(env.new_string(plaintext)?.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1decrypt_1bytes(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    message_ptr: jlong,
+) -> jbyteArray {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<Session>(my_ptr)?;
+
+        let message = handles::get::<OlmMessage>(message_ptr)?;
+        let plaintext = session.lock().decrypt_bytes(&message.lock())?;
+
+        Ok(env.byte_array_from_slice(&plaintext)?.into_raw())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1decrypt_1buffer(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+    message_ptr: jlong,
+    buffer: JByteBuffer,
+    offset: jint,
+    length: jint,
+) -> jint {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<Session>(my_ptr)?;
+
+        let message = handles::get::<OlmMessage>(message_ptr)?;
+        let output = unsafe { direct_buffer(env, &buffer, offset, length)? };
+        let plaintext_length = session.lock().decrypt_into(&message.lock(), output)?;
+
+        advance_buffer(env, &buffer, offset as usize + plaintext_length)?;
+
+        Ok(jint::try_from(plaintext_length)?)
+    })
 }
 
 #[no_mangle]
@@ -220,27 +318,172 @@ pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1encrypt<'a>(
     my_ptr: jlong,
     message: JString<'a>,
 ) -> JObject<'a> {
-    let session = unsafe { &mut *(my_ptr as *mut Session) };
+    jni_call(&mut env, |env| {
+        let session = handles::get::<Session>(my_ptr)?;
+
+        let plaintext = jstring_to_string(env, message)?;
+        let res = session.lock().encrypt(plaintext);
+
+        res.into_java(env)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1encrypt_1bytes<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    message: JByteArray<'a>,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<Session>(my_ptr)?;
+
+        let plaintext = env.convert_byte_array(message)?;
+        let res = session.lock().encrypt(plaintext);
+
+        res.into_java(env)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSession__1encrypt_1buffer<'a>(
+    mut env: JNIEnv<'a>,
+    _class: JClass,
+    my_ptr: jlong,
+    buffer: JByteBuffer<'a>,
+    offset: jint,
+    length: jint,
+) -> JObject<'a> {
+    jni_call(&mut env, |env| {
+        let session = handles::get::<Session>(my_ptr)?;
 
+        let plaintext = unsafe { direct_buffer(env, &buffer, offset, length)? };
+        let res = session.lock().encrypt(plaintext);
+        advance_buffer(env, &buffer, offset as usize + length as usize)?;
 
-    let crypted = env.get_string(&message).expect("Couldn't get Java string").into();
-    let res = session.encrypt(crypted);
+        res.into_java(env)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+    use crate::test_utils::{key, OTHER_KEY, PICKLE_KEY};
+
+    fn session() -> Session {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+        let one_time_key = bob.one_time_keys()[0].key.to_base64();
+
+        alice
+            .create_outbound_session(bob.curve25519_key(), one_time_key, &OlmSessionConfig::version_2())
+            .unwrap()
+    }
 
-    let java_class = env.find_class("de/cogia/vodozemac/OlmMessage").unwrap();
+    #[test]
+    fn pickle_round_trip() {
+        let session = session();
+        let pickle = session.pickle(&key(PICKLE_KEY));
 
-    let msg_type = res.message_type as jlong;
-    let msg = env.new_string(res.ciphertext).unwrap();
+        let unpickled = Session::from_pickle(pickle, &key(PICKLE_KEY)).unwrap();
 
-    let args: &[JValue] = &[
-        (&msg).into(),
-        (msg_type).into(),
-    ];
+        assert_eq!(session.session_id(), unpickled.session_id());
+    }
+
+    #[test]
+    fn from_pickle_with_wrong_key_fails() {
+        let pickle = session().pickle(&key(PICKLE_KEY));
+
+        assert!(Session::from_pickle(pickle, &key(OTHER_KEY)).is_err());
+    }
 
-    let java_object = env.new_object(
-        java_class,
-        "(Ljava/lang/String;J)V",
-        args
-    ).unwrap();
+    #[test]
+    fn from_pickle_with_truncated_pickle_fails() {
+        let pickle = session().pickle(&key(PICKLE_KEY));
+        let truncated = pickle[..pickle.len() / 2].to_owned();
 
-    java_object.into()
+        assert!(Session::from_pickle(truncated, &key(PICKLE_KEY)).is_err());
+    }
+
+    #[test]
+    fn libolm_and_vodozemac_pickles_are_not_interchangeable() {
+        let pickle = session().pickle(&key(PICKLE_KEY));
+        let account_pickle = Account::new().pickle(&key(PICKLE_KEY));
+
+        assert!(Session::from_libolm_pickle(pickle, PICKLE_KEY).is_err());
+        assert!(Session::from_pickle(account_pickle, &key(PICKLE_KEY)).is_err());
+    }
+
+    #[test]
+    fn session_introspection() {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+        let one_time_key = bob.one_time_keys()[0].key.to_base64();
+
+        let mut outbound = alice
+            .create_outbound_session(bob.curve25519_key(), one_time_key.clone(), &OlmSessionConfig::version_2())
+            .unwrap();
+        assert!(!outbound.has_received_message());
+
+        let keys = outbound.session_keys();
+        assert_eq!(keys.identity_key.to_base64(), alice.curve25519_key());
+        assert_eq!(keys.one_time_key.to_base64(), one_time_key);
+        assert_eq!(keys.session_id(), outbound.session_id());
+
+        let message = outbound.encrypt("Hello");
+        let mut inbound = Session {
+            inner: bob.create_inbound_session(alice.curve25519_key(), &message).unwrap().session,
+        };
+        assert!(inbound.has_received_message());
+        assert!(inbound.session_keys() == keys);
+
+        outbound.decrypt(&inbound.encrypt("Hi")).unwrap();
+        assert!(outbound.has_received_message());
+
+        let description = outbound.describe();
+        assert!(description.contains(&outbound.session_id()));
+        assert!(description.contains("chain_index"));
+    }
+
+    #[test]
+    fn undersized_buffers_keep_the_message_decryptable() {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+        let one_time_key = bob.one_time_keys()[0].key.to_base64();
+        let mut outbound = alice
+            .create_outbound_session(bob.curve25519_key(), one_time_key, &OlmSessionConfig::version_2())
+            .unwrap();
+        let message = outbound.encrypt("Hello");
+        let mut inbound = Session {
+            inner: bob.create_inbound_session(alice.curve25519_key(), &message).unwrap().session,
+        };
+        let reply = inbound.encrypt("A reply that is longer than one block");
+
+        for too_small in [4, reply.max_plaintext_length() - 1] {
+            let mut output = vec![0; too_small];
+            assert!(matches!(outbound.decrypt_into(&reply, &mut output), Err(OlmError::InvalidArgument(_))));
+        }
+
+        let mut output = vec![0; reply.max_plaintext_length()];
+        let length = outbound.decrypt_into(&reply, &mut output).unwrap();
+        assert_eq!(&output[..length], b"A reply that is longer than one block");
+    }
+
+    #[test]
+    fn outbound_session_uses_requested_config() {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+        let one_time_key = bob.one_time_keys()[0].key.to_base64();
+
+        let session = alice
+            .create_outbound_session(bob.curve25519_key(), one_time_key, &OlmSessionConfig::version_1())
+            .unwrap();
+
+        assert_eq!(session.session_config(), OlmSessionConfig::version_1());
+    }
 }
@@ -0,0 +1,153 @@
+use jni::JNIEnv;
+use jni::objects::JClass;
+use jni::sys::jlong;
+use super::{handles, jni_call};
+
+/// Configuration of an Olm (1:1) session, the Olm and Megolm versions are
+/// negotiated independently so they get their own types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OlmSessionConfig {
+    pub(crate) inner: vodozemac::olm::SessionConfig,
+}
+
+impl OlmSessionConfig {
+    /// Get the numeric version of this `OlmSessionConfig`.
+    pub const fn version(&self) -> u8 {
+        self.inner.version()
+    }
+
+    /// Create a `OlmSessionConfig` for the Olm version 1. This version of Olm
+    /// will use AES-256 and HMAC with a truncated MAC to encrypt individual
+    /// messages. The MAC will be truncated to 8 bytes.
+    pub const fn version_1() -> Self {
+        Self { inner: vodozemac::olm::SessionConfig::version_1() }
+    }
+
+    /// Create a `OlmSessionConfig` for the Olm version 2. This version of Olm
+    /// will use AES-256 and HMAC to encrypt individual messages. The MAC won't
+    /// be truncated.
+    pub const fn version_2() -> Self {
+        Self { inner: vodozemac::olm::SessionConfig::version_2() }
+    }
+}
+
+impl From<vodozemac::olm::SessionConfig> for OlmSessionConfig {
+    fn from(inner: vodozemac::olm::SessionConfig) -> Self {
+        Self { inner }
+    }
+}
+
+/// Configuration of a Megolm (group) session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MegolmSessionConfig {
+    pub(crate) inner: vodozemac::megolm::SessionConfig,
+}
+
+impl MegolmSessionConfig {
+    /// Get the numeric version of this `MegolmSessionConfig`.
+    pub const fn version(&self) -> u8 {
+        self.inner.version()
+    }
+
+    /// Create a `MegolmSessionConfig` for the Megolm version 1. This version
+    /// of Megolm will use AES-256 and HMAC with a truncated MAC to encrypt
+    /// individual messages. The MAC will be truncated to 8 bytes.
+    pub const fn version_1() -> Self {
+        Self { inner: vodozemac::megolm::SessionConfig::version_1() }
+    }
+
+    /// Create a `MegolmSessionConfig` for the Megolm version 2. This version
+    /// of Megolm will use AES-256 and HMAC to encrypt individual messages. The
+    /// MAC won't be truncated.
+    pub const fn version_2() -> Self {
+        Self { inner: vodozemac::megolm::SessionConfig::version_2() }
+    }
+}
+
+impl From<vodozemac::megolm::SessionConfig> for MegolmSessionConfig {
+    fn from(inner: vodozemac::megolm::SessionConfig) -> Self {
+        Self { inner }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSessionConfig__1version1(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    jni_call(&mut env, |_| Ok(handles::insert(OlmSessionConfig::version_1())))
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSessionConfig__1version2(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    jni_call(&mut env, |_| Ok(handles::insert(OlmSessionConfig::version_2())))
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSessionConfig__1version(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let config = handles::get::<OlmSessionConfig>(my_ptr)?;
+        let version = config.lock().version();
+        Ok(version as jlong)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_OlmSessionConfig__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        handles::free_handle::<OlmSessionConfig>(my_ptr);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmSessionConfig__1version1(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    jni_call(&mut env, |_| Ok(handles::insert(MegolmSessionConfig::version_1())))
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmSessionConfig__1version2(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    jni_call(&mut env, |_| Ok(handles::insert(MegolmSessionConfig::version_2())))
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmSessionConfig__1version(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) -> jlong {
+    jni_call(&mut env, |_| {
+        let config = handles::get::<MegolmSessionConfig>(my_ptr)?;
+        let version = config.lock().version();
+        Ok(version as jlong)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn Java_de_cogia_vodozemac_MegolmSessionConfig__1free(
+    mut env: JNIEnv,
+    _class: JClass,
+    my_ptr: jlong,
+) {
+    jni_call(&mut env, |_| {
+        handles::free_handle::<MegolmSessionConfig>(my_ptr);
+        Ok(())
+    })
+}
@@ -0,0 +1,10 @@
+//! Fixtures shared by the unit tests.
+
+use super::pickle_key::PickleKey;
+
+pub const PICKLE_KEY: &[u8; 32] = b"DEFAULT_PICKLE_KEY_1234567890123";
+pub const OTHER_KEY: &[u8; 32] = b"ANOTHER_PICKLE_KEY_1234567890123";
+
+pub fn key(bytes: &[u8]) -> PickleKey {
+    PickleKey::from_bytes(bytes).unwrap()
+}